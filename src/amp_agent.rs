@@ -1,25 +1,47 @@
 use agent_client_protocol::{
-    Agent, AgentCapabilities, AgentSideConnection, AuthMethod, AuthMethodId, AuthenticateRequest,
-    AuthenticateResponse, CancelNotification, Client, ContentBlock, Diff, EmbeddedResourceResource,
+    Agent, AgentCapabilities, AgentSideConnection, AuthenticateRequest, AuthenticateResponse,
+    CancelNotification, Client, ClientCapabilities, ContentBlock, CreateTerminalRequest, Diff,
     Error, ExtNotification, ExtRequest, ExtResponse, InitializeRequest, InitializeResponse,
-    LoadSessionRequest, LoadSessionResponse, McpCapabilities, McpServer, NewSessionRequest,
-    NewSessionResponse, Plan, PlanEntry, PlanEntryPriority, PlanEntryStatus, PromptCapabilities,
-    PromptRequest, PromptResponse, SessionId, SessionNotification, SessionUpdate,
-    SetSessionModeRequest, SetSessionModeResponse, StopReason, TextContent, ToolCall,
-    ToolCallContent, ToolCallId, ToolCallLocation, ToolCallStatus, ToolCallUpdate,
-    ToolCallUpdateFields, ToolKind, V1,
+    KillTerminalCommandRequest, LoadSessionRequest, LoadSessionResponse, McpCapabilities,
+    NewSessionRequest, NewSessionResponse, Plan, PlanEntry, PlanEntryPriority, PlanEntryStatus,
+    PromptCapabilities, PromptRequest, PromptResponse, ReadTextFileRequest, ReleaseTerminalRequest,
+    RequestPermissionOutcome, RequestPermissionRequest, SessionId, SessionNotification,
+    SessionUpdate, SetSessionModeRequest, SetSessionModeResponse, StopReason, TerminalExitStatus,
+    TerminalOutputRequest, TerminalOutputResponse, TextContent, ToolCall, ToolCallContent,
+    ToolCallId, ToolCallLocation, ToolCallStatus, ToolCallUpdate, ToolCallUpdateFields, ToolKind,
+    WaitForTerminalExitRequest, WriteTextFileRequest, V1,
 };
+use futures::stream::{FuturesUnordered, StreamExt};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
-use std::process::{Child, Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
 use tokio::sync::OnceCell;
+use tokio_util::sync::CancellationToken;
 use tracing::error;
 
+use crate::attachments::TurnAttachments;
+use crate::audio::{self, AudioHandler};
+use crate::auth::{self, ApiKey};
+use crate::bridge::{self, Bridge, BridgeConnection, BridgeRequest, BridgeResponse};
+use crate::mcp_server;
+use crate::mode::{self, AmpMode};
+use crate::permissions::{self, PermissionDecision};
+use crate::policy::{Policy, PolicyAction};
+use crate::process;
+use crate::prompt::PromptBuilder;
+use crate::session::{Session, TurnState};
+use crate::settings;
+use crate::tool_result;
+use crate::usage::{AmpResult, Usage};
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct AmpConversation {
@@ -47,9 +69,8 @@ pub enum AmpStreamEvent {
         session_id: String,
     },
     Result {
-        subtype: String,
         #[serde(flatten)]
-        data: serde_json::Value,
+        result: AmpResult,
     },
 }
 
@@ -76,6 +97,8 @@ pub enum AmpContentBlock {
     Thinking(AmpThinkingContentBlock),
     ToolUse(AmpToolUseContentBlock),
     ToolResult(AmpToolResultContentBlock),
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -103,7 +126,97 @@ pub struct AmpToolUseContentBlock {
 pub struct AmpToolResultContentBlock {
     #[serde(rename = "toolUseID")]
     pub tool_use_id: String,
-    pub run: serde_json::Value,
+    pub run: AmpToolRun,
+}
+
+/// A tool run, with the JSON amp sent for it kept as-is for `raw_output`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "serde_json::Value", into = "serde_json::Value")]
+pub struct AmpToolRun {
+    pub status: AmpToolRunStatus,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<serde_json::Value>,
+    pub raw: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct AmpToolRunFields {
+    // A result without a status has nothing left to run.
+    #[serde(default = "AmpToolRunStatus::done")]
+    status: AmpToolRunStatus,
+    #[serde(default)]
+    result: Option<serde_json::Value>,
+    #[serde(default)]
+    error: Option<serde_json::Value>,
+}
+
+impl TryFrom<serde_json::Value> for AmpToolRun {
+    type Error = serde_json::Error;
+
+    fn try_from(raw: serde_json::Value) -> Result<Self, Self::Error> {
+        let fields = AmpToolRunFields::deserialize(&raw)?;
+        Ok(Self {
+            status: fields.status,
+            result: fields.result,
+            error: fields.error,
+            raw,
+        })
+    }
+}
+
+impl From<AmpToolRun> for serde_json::Value {
+    fn from(run: AmpToolRun) -> Self {
+        run.raw
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AmpToolRunStatus {
+    Done,
+    Error,
+    RejectedByUser,
+    Cancelled,
+    InProgress,
+    Queued,
+    BlockedOnUser,
+    /// A state we don't know, which is never taken as the end of the run.
+    #[serde(other)]
+    Other,
+}
+
+impl AmpToolRunStatus {
+    fn done() -> Self {
+        Self::Done
+    }
+}
+
+impl AmpToolRun {
+    pub fn failed(&self) -> bool {
+        matches!(
+            self.status,
+            AmpToolRunStatus::Error
+                | AmpToolRunStatus::RejectedByUser
+                | AmpToolRunStatus::Cancelled
+        )
+    }
+
+    /// What to show for a failed run: amp's error if it gave one, otherwise the status.
+    pub fn error_message(&self) -> String {
+        let error = self.error.as_ref().map(|error| {
+            error
+                .get("message")
+                .unwrap_or(error)
+                .as_str()
+                .map_or_else(|| error.to_string(), str::to_string)
+        });
+        match (error, &self.status) {
+            (Some(error), _) => error,
+            (None, AmpToolRunStatus::RejectedByUser) => "Rejected by the user".to_string(),
+            (None, AmpToolRunStatus::Cancelled) => "Cancelled".to_string(),
+            (None, _) => "The tool failed".to_string(),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -176,102 +289,7 @@ impl AmpPlanWriteToolCall {
     }
 }
 
-pub trait AmpDiff<T> {
-    fn diff(&self, other: &T) -> Option<T>;
-}
-
-impl AmpDiff<AmpConversation> for AmpConversation {
-    fn diff(&self, other: &AmpConversation) -> Option<AmpConversation> {
-        let num_diff = other.messages.len() - self.messages.len();
-        let messages_diff: Vec<Option<AmpMessage>> = self
-            .messages
-            .iter()
-            .zip(other.messages.iter())
-            .map(|(a, b)| a.diff(b))
-            .collect();
-
-        let mut f: Vec<AmpMessage> = messages_diff.iter().filter_map(|m| m.clone()).collect();
-
-        if num_diff > 0 {
-            //take the last num_diff items from other
-            let mut rem: Vec<AmpMessage> = other
-                .messages
-                .iter()
-                .cloned()
-                .rev()
-                .take(num_diff)
-                .collect();
-            f.append(&mut rem);
-        }
-        Some(AmpConversation { messages: f })
-    }
-}
-
-impl AmpDiff<AmpContentBlock> for AmpContentBlock {
-    fn diff(&self, other: &AmpContentBlock) -> Option<AmpContentBlock> {
-        match (self, other) {
-            (AmpContentBlock::Text(a), AmpContentBlock::Text(b)) => {
-                if a.text == b.text {
-                    None
-                } else {
-                    Some(AmpContentBlock::Text(AmpTextContentBlock {
-                        text: b.text.replace(&a.text, ""),
-                    }))
-                }
-            }
-            (AmpContentBlock::Thinking(a), AmpContentBlock::Thinking(b)) => {
-                if a.thinking == b.thinking {
-                    None
-                } else {
-                    Some(AmpContentBlock::Thinking(AmpThinkingContentBlock {
-                        thinking: b.thinking.replace(&a.thinking, ""),
-                    }))
-                }
-            }
-            (AmpContentBlock::ToolUse(a), AmpContentBlock::ToolUse(b)) => {
-                if a.id == b.id && a.name == b.name && a.input == b.input {
-                    None
-                } else {
-                    Some(AmpContentBlock::ToolUse(AmpToolUseContentBlock {
-                        id: b.id.clone(),
-                        name: b.name.clone(),
-                        input: b.input.clone(),
-                    }))
-                }
-            }
-            _ => None,
-        }
-    }
-}
-
-impl AmpDiff<AmpMessage> for AmpMessage {
-    fn diff(&self, other: &AmpMessage) -> Option<AmpMessage> {
-        let num_diff = other.content.len() - self.content.len();
-        if self.role == other.role {
-            let mut content_diff: Vec<AmpContentBlock> = self
-                .content
-                .iter()
-                .zip(other.content.iter())
-                .filter_map(|(a, b)| a.diff(b))
-                .collect();
-
-            if num_diff > 0 {
-                //take the last num_diff items from other
-                let mut rem: Vec<AmpContentBlock> =
-                    other.content.iter().cloned().rev().take(num_diff).collect();
-                content_diff.append(&mut rem);
-            }
-            Some(AmpMessage {
-                role: self.role.clone(),
-                content: content_diff,
-            })
-        } else {
-            None
-        }
-    }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum AmpTool {
     Bash,
     #[serde(rename = "create_file")]
@@ -307,20 +325,20 @@ pub enum AmpTool {
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AmpReadToolInput {
-    path: String,
-    read_range: Option<Vec<i32>>,
+    pub path: String,
+    pub read_range: Option<Vec<i32>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AmpCreateToolInput {
-    path: String,
-    content: String,
+    pub path: String,
+    pub content: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct AmpBashToolInput {
-    cmd: String,
-    cwd: Option<String>,
+    pub cmd: String,
+    pub cwd: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -361,7 +379,7 @@ impl std::fmt::Display for AmpTool {
     }
 }
 
-fn amp_tool_to_tool_kind(amp_tool: &AmpTool) -> ToolKind {
+pub fn amp_tool_to_tool_kind(amp_tool: &AmpTool) -> ToolKind {
     match amp_tool {
         AmpTool::Bash => ToolKind::Execute,
         AmpTool::CreateFile => ToolKind::Edit,
@@ -383,18 +401,41 @@ fn amp_tool_to_tool_kind(amp_tool: &AmpTool) -> ToolKind {
     }
 }
 
+#[derive(Default)]
+struct TurnContext {
+    file_edits: HashMap<String, AmpEditFileToolCall>,
+    // Tool calls the client was told about that haven't finished, with their last status.
+    open_tool_calls: HashMap<String, ToolCallStatus>,
+    // Tool uses amp has announced but not reported a result for, used to match
+    // permission requests coming in over the bridge to the tool call they belong to.
+    pending_tool_uses: HashMap<String, (AmpTool, serde_json::Value)>,
+    synthetic_tool_calls: usize,
+    // Tool calls already reported as failed because the policy blocked them.
+    denied_tool_calls: HashSet<String>,
+    // Tool calls showing a client terminal, whose result must not replace it.
+    terminal_tool_calls: HashSet<String>,
+}
+
 pub struct AmpAgent {
-    cwd: Rc<RefCell<Option<PathBuf>>>,
     client: OnceCell<Rc<AgentSideConnection>>,
-    amp_command: Rc<RefCell<Option<Child>>>,
+    sessions: Rc<RefCell<HashMap<SessionId, Session>>>,
+    cancel_grace: Duration,
+    audio_handler: Option<Rc<dyn AudioHandler>>,
+    policy: Policy,
+    client_capabilities: RefCell<ClientCapabilities>,
+    api_key: RefCell<Option<ApiKey>>,
 }
 
 impl AmpAgent {
-    pub fn new() -> Self {
+    pub fn new(policy: Policy) -> Self {
         Self {
-            cwd: Rc::new(RefCell::new(None)),
             client: OnceCell::new(),
-            amp_command: Rc::new(RefCell::new(None)),
+            sessions: Rc::new(RefCell::new(HashMap::new())),
+            cancel_grace: process::cancel_grace_period(),
+            audio_handler: audio::handler_from_env(),
+            policy,
+            client_capabilities: RefCell::new(ClientCapabilities::default()),
+            api_key: RefCell::new(None),
         }
     }
 
@@ -402,103 +443,18 @@ impl AmpAgent {
         let _ = self.client.set(client);
     }
 
-    pub fn set_amp_command(&self, command: Child) {
-        self.amp_command.replace(Some(command));
-    }
-
     pub fn client(&self) -> Rc<AgentSideConnection> {
         Rc::clone(self.client.get().expect("Client should be set"))
     }
 
-    fn get_amp_settings_path(&self) -> PathBuf {
-        if cfg!(target_os = "windows") {
-            let appdata = env::var("APPDATA").unwrap_or_else(|_| ".".to_string());
-            PathBuf::from(appdata).join("amp").join("settings.json")
-        } else {
-            let home = env::var("HOME").unwrap_or_else(|_| ".".to_string());
-            PathBuf::from(home)
-                .join(".config")
-                .join("amp")
-                .join("settings.json")
-        }
+    fn amp_command(&self) -> Command {
+        auth::amp_command(self.api_key.borrow().as_ref())
     }
 
-    fn write_mcp_servers(
-        &self,
-        settings_path: &PathBuf,
-        servers: &[McpServer],
-    ) -> Result<(), std::io::Error> {
-        let mut config = if settings_path.exists() {
-            let contents = std::fs::read_to_string(settings_path)?;
-            serde_json::from_str::<serde_json::Value>(&contents)
-                .unwrap_or_else(|_| serde_json::json!({}))
-        } else {
-            if let Some(parent) = settings_path.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
-            serde_json::json!({})
-        };
-
-        let mcp_servers = servers
-            .iter()
-            .map(|server| match server {
-                McpServer::Stdio {
-                    name,
-                    command,
-                    args,
-                    env,
-                } => {
-                    let env_obj: serde_json::Map<String, serde_json::Value> = env
-                        .iter()
-                        .map(|e| (e.name.clone(), serde_json::Value::String(e.value.clone())))
-                        .collect();
-
-                    (
-                        name.clone(),
-                        serde_json::json!({
-                            "command": command,
-                            "args": args,
-                            "env": env_obj
-                        }),
-                    )
-                }
-                McpServer::Http { name, url, headers } => {
-                    let headers_obj: serde_json::Map<String, serde_json::Value> = headers
-                        .iter()
-                        .map(|h| (h.name.clone(), serde_json::Value::String(h.value.clone())))
-                        .collect();
-
-                    (
-                        name.clone(),
-                        serde_json::json!({
-                            "type": "http",
-                            "url": url,
-                            "headers": headers_obj
-                        }),
-                    )
-                }
-                McpServer::Sse { name, url, headers } => {
-                    let headers_obj: serde_json::Map<String, serde_json::Value> = headers
-                        .iter()
-                        .map(|h| (h.name.clone(), serde_json::Value::String(h.value.clone())))
-                        .collect();
-
-                    (
-                        name.clone(),
-                        serde_json::json!({
-                            "type": "sse",
-                            "url": url,
-                            "headers": headers_obj
-                        }),
-                    )
-                }
-            })
-            .collect::<serde_json::Map<String, serde_json::Value>>();
-
-        config["amp.mcpServers"] = serde_json::Value::Object(mcp_servers);
-
-        std::fs::write(settings_path, serde_json::to_string_pretty(&config)?)?;
-        Ok(())
+    /// Drops every session once the connection is gone, which stops any amp still running
+    /// and removes the sessions' settings files.
+    pub fn shutdown(&self) {
+        (*self.sessions).borrow_mut().clear();
     }
 
     fn extract_thinking(text: &str) -> (Vec<String>, Vec<String>) {
@@ -534,7 +490,7 @@ impl AmpAgent {
         &self,
         message: &AmpMessage,
         session_id: SessionId,
-        file_edits: &mut HashMap<String, AmpEditFileToolCall>,
+        turn: &RefCell<TurnContext>,
         parent_tool_use_id: Option<String>,
     ) {
         for block in &message.content {
@@ -620,6 +576,14 @@ impl AmpAgent {
                     }
                 }
                 AmpContentBlock::ToolUse(tool_use_content_block) => {
+                    turn.borrow_mut().pending_tool_uses.insert(
+                        tool_use_content_block.id.clone(),
+                        (
+                            tool_use_content_block.name.clone(),
+                            tool_use_content_block.input.clone(),
+                        ),
+                    );
+
                     let mut title = tool_use_content_block.name.to_string();
                     let mut content = vec![];
                     let mut meta = None;
@@ -643,11 +607,19 @@ impl AmpAgent {
                                 serde_json::from_value(tool_use_content_block.input.clone());
 
                             if let Ok(data) = data {
-                                file_edits
+                                title = format!("Edit {}", data.path);
+                                content = vec![ToolCallContent::Diff {
+                                    diff: Diff {
+                                        path: PathBuf::from(&data.path),
+                                        old_text: data.old_str.clone(),
+                                        new_text: data.new_str.clone(),
+                                        meta: None,
+                                    },
+                                }];
+                                turn.borrow_mut()
+                                    .file_edits
                                     .entry(tool_use_content_block.id.clone())
                                     .or_insert(data);
-
-                                continue;
                             }
                         }
                         AmpTool::TodoWrite => {
@@ -734,12 +706,22 @@ impl AmpAgent {
                         _ => {}
                     }
 
+                    // Amp starts right away on tools it doesn't ask us about first.
+                    let status = if self.awaits_permission(&tool_use_content_block.name) {
+                        ToolCallStatus::Pending
+                    } else {
+                        ToolCallStatus::InProgress
+                    };
+                    turn.borrow_mut()
+                        .open_tool_calls
+                        .insert(tool_use_content_block.id.clone(), status);
+
                     let notification = SessionNotification {
                         session_id: session_id.clone(),
                         update: SessionUpdate::ToolCall(ToolCall {
                             id: ToolCallId(Arc::from(tool_use_content_block.id.clone())),
                             kind: amp_tool_to_tool_kind(&tool_use_content_block.name),
-                            status: ToolCallStatus::Pending,
+                            status,
                             title,
                             content,
                             locations: vec![],
@@ -758,10 +740,70 @@ impl AmpAgent {
                     let update;
                     let mut line = None;
 
-                    if let Some(file_edit) =
-                        file_edits.remove(&tool_result_content_block.tool_use_id)
-                    {
-                        if let Some(result) = &tool_result_content_block.run.get("result") {
+                    match tool_result_content_block.run.status {
+                        // Still waiting for the user's go-ahead, so it stays pending.
+                        AmpToolRunStatus::Queued | AmpToolRunStatus::BlockedOnUser => continue,
+                        AmpToolRunStatus::InProgress | AmpToolRunStatus::Other => {
+                            self.mark_in_progress(
+                                session_id.clone(),
+                                turn,
+                                &tool_result_content_block.tool_use_id,
+                            )
+                            .await;
+                            continue;
+                        }
+                        AmpToolRunStatus::Done
+                        | AmpToolRunStatus::Error
+                        | AmpToolRunStatus::RejectedByUser
+                        | AmpToolRunStatus::Cancelled => {}
+                    }
+
+                    let (tool_use, denied, file_edit, in_terminal) = {
+                        let mut turn = turn.borrow_mut();
+                        let id = &tool_result_content_block.tool_use_id;
+                        turn.open_tool_calls.remove(id);
+                        (
+                            turn.pending_tool_uses.remove(id),
+                            turn.denied_tool_calls.remove(id),
+                            turn.file_edits.remove(id),
+                            turn.terminal_tool_calls.remove(id),
+                        )
+                    };
+                    if denied {
+                        continue;
+                    }
+
+                    let run = &tool_result_content_block.run;
+                    let raw_output = Some(run.raw.clone());
+
+                    if run.failed() {
+                        // The terminal already shows what went wrong.
+                        let content = (!in_terminal).then(|| {
+                            vec![ToolCallContent::Content {
+                                content: ContentBlock::Text(TextContent {
+                                    text: run.error_message(),
+                                    annotations: None,
+                                    meta: None,
+                                }),
+                            }]
+                        });
+                        update = ToolCallUpdate {
+                            id: ToolCallId(Arc::from(
+                                tool_result_content_block.tool_use_id.clone(),
+                            )),
+                            fields: ToolCallUpdateFields {
+                                content,
+                                kind: None,
+                                status: Some(ToolCallStatus::Failed),
+                                title: None,
+                                locations: None,
+                                raw_input: None,
+                                raw_output,
+                            },
+                            meta: None,
+                        };
+                    } else if let Some(file_edit) = file_edit {
+                        if let Some(result) = &run.result {
                             if let Some(diff) = result.get("diff") {
                                 if let Some(diff_str) = diff.as_str() {
                                     line = get_line_number_from_diff_str(diff_str);
@@ -790,29 +832,35 @@ impl AmpAgent {
                                     meta: None,
                                 }]),
                                 raw_input: None,
-                                raw_output: None,
+                                raw_output,
                             },
                             meta: None,
                         };
                     } else {
+                        // The terminal already shows the output.
+                        let content = run.result.as_ref().filter(|_| !in_terminal).map(|result| {
+                            let (tool, input) =
+                                tool_use.as_ref().map(|(tool, input)| (tool, input)).unzip();
+                            vec![ToolCallContent::Content {
+                                content: ContentBlock::Text(TextContent {
+                                    text: tool_result::format(tool, input, result),
+                                    annotations: None,
+                                    meta: None,
+                                }),
+                            }]
+                        });
                         update = ToolCallUpdate {
                             id: ToolCallId(Arc::from(
                                 tool_result_content_block.tool_use_id.clone(),
                             )),
                             fields: ToolCallUpdateFields {
-                                content: Some(vec![ToolCallContent::Content {
-                                    content: ContentBlock::Text(TextContent {
-                                        text: tool_result_content_block.run.to_string(),
-                                        annotations: None,
-                                        meta: None,
-                                    }),
-                                }]),
+                                content,
                                 kind: None,
                                 status: Some(ToolCallStatus::Completed),
                                 title: None,
                                 locations: None,
                                 raw_input: None,
-                                raw_output: None,
+                                raw_output,
                             },
                             meta: None,
                         };
@@ -830,260 +878,1235 @@ impl AmpAgent {
                         error!("Failed to send session notification: {:?}", e);
                     }
                 }
+                AmpContentBlock::Other => {}
             }
         }
     }
-}
 
-fn get_line_number_from_diff_str(diff: &str) -> Option<u32> {
-    let parts = diff.split("@@").collect::<Vec<&str>>();
-    let header = parts.get(1)?.trim();
-    let line_info_parts = header.split(" ").collect::<Vec<&str>>();
-    let final_line_number = line_info_parts.get(1)?;
-    let line_number_parts = final_line_number.split(",").collect::<Vec<&str>>();
-    let line_number = line_number_parts.first()?.replace("+", "").parse::<u32>();
+    /// Tools amp hands to the permission delegate before running them.
+    fn awaits_permission(&self, tool: &AmpTool) -> bool {
+        permissions::GATED_TOOLS.contains(tool) || self.policy.tools().contains(tool)
+    }
 
-    line_number.ok()
-}
+    async fn mark_in_progress(
+        &self,
+        session_id: SessionId,
+        turn: &RefCell<TurnContext>,
+        tool_use_id: &str,
+    ) {
+        match turn.borrow_mut().open_tool_calls.get_mut(tool_use_id) {
+            Some(status) if *status != ToolCallStatus::InProgress => {
+                *status = ToolCallStatus::InProgress;
+            }
+            _ => return,
+        }
 
-#[async_trait::async_trait(?Send)]
-impl Agent for AmpAgent {
-    async fn initialize(&self, _request: InitializeRequest) -> Result<InitializeResponse, Error> {
-        return Ok(InitializeResponse {
+        let update = ToolCallUpdate {
+            id: ToolCallId(Arc::from(tool_use_id)),
+            fields: ToolCallUpdateFields {
+                status: Some(ToolCallStatus::InProgress),
+                ..Default::default()
+            },
             meta: None,
-            protocol_version: V1,
-            agent_capabilities: AgentCapabilities {
-                load_session: false,
-                prompt_capabilities: PromptCapabilities {
-                    image: true,
-                    audio: true,
-                    embedded_context: true,
-                    meta: None,
-                },
-                mcp_capabilities: McpCapabilities {
-                    http: true,
-                    sse: true,
-                    meta: None,
-                },
+        };
+        if let Err(e) = self
+            .client()
+            .session_notification(SessionNotification {
+                session_id,
+                update: SessionUpdate::ToolCallUpdate(update),
                 meta: None,
-            },
-            auth_methods: vec![AuthMethod {
-                id: AuthMethodId(Arc::from("api-key")),
-                name: "API Key".to_string(),
-                description: Some(
-                    "Amp API key (set via AMP_API_KEY env var or run 'amp login')".to_string(),
-                ),
+            })
+            .await
+        {
+            error!("Failed to send session notification: {:?}", e);
+        }
+    }
+
+    async fn fail_open_tool_calls(&self, session_id: &SessionId, turn: &RefCell<TurnContext>) {
+        let open: Vec<String> = turn
+            .borrow_mut()
+            .open_tool_calls
+            .drain()
+            .map(|(id, _)| id)
+            .collect();
+        for tool_use_id in open {
+            let update = ToolCallUpdate {
+                id: ToolCallId(Arc::from(tool_use_id)),
+                fields: ToolCallUpdateFields {
+                    status: Some(ToolCallStatus::Failed),
+                    ..Default::default()
+                },
                 meta: None,
-            }],
-        });
+            };
+
+            if let Err(e) = self
+                .client()
+                .session_notification(SessionNotification {
+                    session_id: session_id.clone(),
+                    update: SessionUpdate::ToolCallUpdate(update),
+                    meta: None,
+                })
+                .await
+            {
+                error!("Failed to send session notification: {:?}", e);
+            }
+        }
     }
 
-    async fn authenticate(
+    async fn wait_for_interrupted(
         &self,
-        _request: AuthenticateRequest,
-    ) -> Result<AuthenticateResponse, Error> {
-        Ok(AuthenticateResponse { meta: None })
+        child: &mut Child,
+    ) -> std::io::Result<std::process::ExitStatus> {
+        let pgid = child.id();
+        let status = match tokio::time::timeout(self.cancel_grace, child.wait()).await {
+            Ok(status) => status,
+            Err(_) => {
+                if let Some(pgid) = pgid {
+                    process::kill_group(pgid)?;
+                }
+                child.wait().await
+            }
+        };
+
+        // Sweep up anything amp spawned that outlived it, e.g. a running `cargo build`.
+        if let Some(pgid) = pgid {
+            if let Err(e) = process::kill_group(pgid) {
+                error!("Failed to kill amp process group: {:?}", e);
+            }
+        }
+
+        status
     }
 
-    async fn new_session(&self, request: NewSessionRequest) -> Result<NewSessionResponse, Error> {
-        (*self.cwd).borrow_mut().replace(request.cwd.clone());
+    fn validate_cwd(&self, cwd: &Path) -> Result<(), Error> {
+        if !cwd.is_absolute() {
+            return Err(Error::invalid_params().with_data(format!(
+                "Session cwd must be an absolute path: {}",
+                cwd.display()
+            )));
+        }
+        if !cwd.is_dir() {
+            return Err(Error::invalid_params().with_data(format!(
+                "Session cwd does not exist or is not a directory: {}",
+                cwd.display()
+            )));
+        }
+        Ok(())
+    }
 
-        Command::new("amp")
-            .current_dir(request.cwd.clone())
+    async fn check_amp_installed(&self, cwd: &Path) -> Result<(), Error> {
+        self.amp_command()
+            .current_dir(cwd)
             .args(["--version"])
             .output()
+            .await
             .map_err(|_| {
                 Error::invalid_request().with_data(
                     "Amp is not installed: curl -fsSL https://ampcode.com/install.sh | bash",
                 )
             })?;
+        Ok(())
+    }
 
-        if !request.mcp_servers.is_empty() {
-            let settings_path = self.get_amp_settings_path();
-            if let Err(e) = self.write_mcp_servers(&settings_path, &request.mcp_servers) {
-                error!("Failed to configure MCP servers: {:?}", e);
-            }
-        }
-
-        let output = Command::new("amp")
-            .current_dir(request.cwd.clone())
-            .args(["threads", "new"])
+    async fn get_amp_thread(
+        &self,
+        session_id: &SessionId,
+        cwd: &Path,
+    ) -> Result<AmpConversation, Error> {
+        let output = self
+            .amp_command()
+            .current_dir(cwd)
+            .args(["threads", "export", &session_id.0])
             .output()
+            .await
             .map_err(Error::into_internal_error)?;
 
-        let session_id = match String::from_utf8(output.stdout) {
-            Ok(s) => Some(s.replace("\n", "")),
-            Err(_) => None,
-        };
-
-        if let Some(session_id) = session_id {
-            Ok(NewSessionResponse {
-                session_id: SessionId(Arc::from(session_id)),
-                modes: None,
-                meta: None,
-            })
-        } else {
-            Err(Error::internal_error())
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if auth::is_auth_failure(&stderr) {
+                return Err(auth::auth_required(&stderr));
+            }
+            return Err(Error::resource_not_found(None).with_data(format!(
+                "Could not open amp thread {}: {}",
+                session_id.0,
+                stderr.trim()
+            )));
         }
+
+        serde_json::from_slice(&output.stdout).map_err(|e| {
+            Error::internal_error().with_data(format!("Failed to parse amp thread: {e}"))
+        })
     }
 
-    async fn load_session(
+    async fn handle_stream_event(
         &self,
-        _request: LoadSessionRequest,
-    ) -> Result<LoadSessionResponse, Error> {
-        todo!()
-        // Loading sessions is not currently suppored by Zed, the code below should be mostly what is needed to support this.
-        // Note: There is an `if message.role != "user" {` that will need to be sorted out as session loading should replay user messages aswell
-        //
-        // if let Some(conversation) = self.get_amp_thread(request.session_id.clone()) {
-        //     self.process_conversation(&conversation, request.session_id)
-        //         .await;
-        //     todo!()
-        // } else {
-        //     Err(Error::internal_error().with_data("Could not open amp thread"))
-        // }
+        event: AmpStreamEvent,
+        session_id: &SessionId,
+        turn: &RefCell<TurnContext>,
+    ) {
+        match event {
+            AmpStreamEvent::System { .. } | AmpStreamEvent::Result { .. } => {}
+            AmpStreamEvent::User {
+                message,
+                parent_tool_use_id,
+                ..
+            } => {
+                self.process_message(&message, session_id.clone(), turn, parent_tool_use_id)
+                    .await;
+            }
+            AmpStreamEvent::Assistant {
+                message,
+                parent_tool_use_id,
+                ..
+            } => {
+                if let Ok(amp_message) = serde_json::from_value::<AmpMessage>(message) {
+                    self.process_message(
+                        &amp_message,
+                        session_id.clone(),
+                        turn,
+                        parent_tool_use_id,
+                    )
+                    .await;
+                }
+            }
+        }
     }
 
-    async fn prompt(&self, request: PromptRequest) -> Result<PromptResponse, Error> {
-        let prompt = request
-            .prompt
+    /// Amp's file and Bash tools that can be replaced by ones backed by the client's filesystem.
+    fn client_backed_tools(&self) -> Vec<AmpTool> {
+        let fs = &self.client_capabilities.borrow().fs;
+        let mut tools = Vec::new();
+        if fs.read_text_file {
+            tools.push(AmpTool::Read);
+        }
+        if fs.write_text_file {
+            tools.push(AmpTool::CreateFile);
+        }
+        if fs.read_text_file && fs.write_text_file {
+            tools.push(AmpTool::EditFile);
+        }
+        if self.client_capabilities.borrow().terminal {
+            tools.push(AmpTool::Bash);
+        }
+        tools
+    }
+
+    fn settings_overlay(
+        &self,
+        mode: AmpMode,
+        mcp_servers: serde_json::Value,
+        bridge: Result<&Bridge, &std::io::Error>,
+    ) -> Result<serde_json::Value, Error> {
+        let mut overlay = serde_json::json!({ "amp.mcpServers": mcp_servers });
+        let mut disabled: Vec<String> = mode
+            .disabled_tools()
             .iter()
-            .map(|b| match b {
-                ContentBlock::Text(text_content) => text_content.text.clone(),
-                ContentBlock::Image(_) | ContentBlock::Audio(_) => String::new(),
-                ContentBlock::ResourceLink(resource_link) => resource_link.uri.clone(),
-                ContentBlock::Resource(embedded_resource) => match &embedded_resource.resource {
-                    EmbeddedResourceResource::TextResourceContents(text_resource_contents) => {
-                        text_resource_contents.text.clone()
-                    }
-                    EmbeddedResourceResource::BlobResourceContents(blob_resource_contents) => {
-                        blob_resource_contents.blob.clone()
+            .map(|t| t.to_string())
+            .collect();
+
+        // Everything below calls back into the adapter, so it needs the bridge. Without it
+        // amp would run gated tools and ignore the policy, so that only goes when neither
+        // applies.
+        let delegate = bridge
+            .map_err(|e| format!("could not open the bridge: {e}"))
+            .and_then(|bridge| {
+                env::current_exe()
+                    .map(|exe| (bridge, exe))
+                    .map_err(|e| format!("could not locate the amp-acp executable: {e}"))
+            });
+        match delegate {
+            Ok((bridge, exe)) => {
+                overlay["amp.permissions"] =
+                    permissions::delegate_rules(&exe, &self.policy.tools());
+
+                let tools = self.client_backed_tools();
+                if !tools.is_empty() {
+                    disabled.extend(
+                        tools
+                            .iter()
+                            .map(|tool| format!("builtin:{}", mcp_server::tool_name(tool))),
+                    );
+                    // Amp's undo only knows about edits made by its own edit_file.
+                    if tools.contains(&AmpTool::EditFile) {
+                        disabled.push("builtin:undo_edit".to_string());
                     }
-                },
-            })
-            .collect::<Vec<String>>()
-            .join("");
+                    overlay["amp.mcpServers"][mcp_server::SERVER_NAME] =
+                        mcp_server::server_config(&exe, bridge.path(), &tools);
+                }
+            }
+            Err(e) if self.needs_delegate(mode) => {
+                return Err(Error::internal_error().with_data(format!(
+                    "Tool approvals and the permission policy can't be enforced ({e}), so amp \
+                     was not started"
+                )));
+            }
+            Err(e) => error!("Running amp without the amp-acp tools: {e}"),
+        }
 
-        let mut child = Command::new("amp")
-            .args([
-                "threads",
-                "continue",
+        if !disabled.is_empty() {
+            overlay["amp.tools.disable"] = serde_json::json!(disabled);
+        }
+        Ok(overlay)
+    }
+
+    /// Whether amp has to hand some tool calls to us before running them: the policy has
+    /// rules, or the mode leaves a tool enabled that needs the user's approval.
+    fn needs_delegate(&self, mode: AmpMode) -> bool {
+        !self.policy.tools().is_empty()
+            || permissions::GATED_TOOLS.iter().any(|tool| {
+                !mode
+                    .disabled_tools()
+                    .contains(&mcp_server::tool_name(tool).as_str())
+            })
+    }
+
+    async fn handle_bridge_connection(
+        &self,
+        mut connection: BridgeConnection,
+        session_id: &SessionId,
+        turn: &RefCell<TurnContext>,
+    ) {
+        let response = match connection.read_request().await {
+            Ok(BridgeRequest::Permission { tool, input }) => {
+                let decision = self
+                    .request_tool_permission(session_id, turn, tool, input)
+                    .await;
+                BridgeResponse::Ok(serde_json::json!(decision))
+            }
+            Ok(BridgeRequest::ReadTextFile { path, line, limit }) => {
+                let request = ReadTextFileRequest {
+                    session_id: session_id.clone(),
+                    path: self.session_path(session_id, path),
+                    line,
+                    limit,
+                    meta: None,
+                };
+                match self.client().read_text_file(request).await {
+                    Ok(response) => BridgeResponse::Ok(serde_json::json!(response.content)),
+                    Err(e) => BridgeResponse::Error(format!("Failed to read file: {e}")),
+                }
+            }
+            Ok(BridgeRequest::Bash { input }) => {
+                match self.run_in_terminal(session_id, turn, input).await {
+                    Ok(output) => BridgeResponse::Ok(serde_json::json!(output)),
+                    Err(e) => BridgeResponse::Error(e),
+                }
+            }
+            Ok(BridgeRequest::WriteTextFile { path, content }) => {
+                let request = WriteTextFileRequest {
+                    session_id: session_id.clone(),
+                    path: self.session_path(session_id, path),
+                    content,
+                    meta: None,
+                };
+                match self.client().write_text_file(request).await {
+                    Ok(_) => BridgeResponse::Ok(serde_json::Value::Null),
+                    Err(e) => BridgeResponse::Error(format!("Failed to write file: {e}")),
+                }
+            }
+            Err(e) => BridgeResponse::Error(format!("Invalid bridge request: {e}")),
+        };
+
+        if let Err(e) = connection.respond(&response).await {
+            error!("Failed to answer bridge request: {:?}", e);
+        }
+    }
+
+    /// Helpers started by amp aren't told which tool use they serve, so match the request
+    /// against the tool uses amp has streamed so far, or make up an id if none fits.
+    fn match_tool_use(turn: &mut TurnContext, tool: &AmpTool, input: &serde_json::Value) -> String {
+        turn.pending_tool_uses
+            .iter()
+            .find(|(_, (name, tool_input))| name == tool && tool_input == input)
+            .map(|(id, _)| id.clone())
+            .unwrap_or_else(|| {
+                turn.synthetic_tool_calls += 1;
+                format!("tool-{}", turn.synthetic_tool_calls)
+            })
+    }
+
+    async fn run_in_terminal(
+        &self,
+        session_id: &SessionId,
+        turn: &RefCell<TurnContext>,
+        input: serde_json::Value,
+    ) -> Result<String, String> {
+        let bash: AmpBashToolInput = serde_json::from_value(input.clone())
+            .map_err(|e| format!("Invalid Bash input: {e}"))?;
+        let (cwd, cancelled) = (*self.sessions)
+            .borrow()
+            .get(session_id)
+            .map(|session| (session.cwd.clone(), session.cancelled.clone()))
+            .ok_or_else(|| format!("Unknown session: {}", session_id.0))?;
+        let cwd = match bash.cwd {
+            Some(dir) => self.session_path(session_id, PathBuf::from(dir)),
+            None => cwd,
+        };
+
+        let client = self.client();
+        let terminal_id = client
+            .create_terminal(CreateTerminalRequest {
+                session_id: session_id.clone(),
+                command: "bash".to_string(),
+                args: vec!["-c".to_string(), bash.cmd.clone()],
+                env: vec![],
+                cwd: Some(cwd),
+                output_byte_limit: Some(TERMINAL_OUTPUT_LIMIT),
+                meta: None,
+            })
+            .await
+            .map_err(|e| format!("Failed to start a terminal: {e}"))?
+            .terminal_id;
+
+        let tool_use_id = Self::match_tool_use(&mut turn.borrow_mut(), &AmpTool::Bash, &input);
+        let content = vec![ToolCallContent::Terminal {
+            terminal_id: terminal_id.clone(),
+        }];
+        let announced = {
+            let mut turn = turn.borrow_mut();
+            turn.terminal_tool_calls.insert(tool_use_id.clone());
+            turn.open_tool_calls
+                .insert(tool_use_id.clone(), ToolCallStatus::InProgress)
+                .is_some()
+        };
+        let update = if announced {
+            SessionUpdate::ToolCallUpdate(ToolCallUpdate {
+                id: ToolCallId(Arc::from(tool_use_id.clone())),
+                fields: ToolCallUpdateFields {
+                    status: Some(ToolCallStatus::InProgress),
+                    content: Some(content),
+                    ..Default::default()
+                },
+                meta: None,
+            })
+        } else {
+            SessionUpdate::ToolCall(ToolCall {
+                id: ToolCallId(Arc::from(tool_use_id.clone())),
+                kind: ToolKind::Execute,
+                status: ToolCallStatus::InProgress,
+                title: bash.cmd,
+                content,
+                locations: vec![],
+                raw_input: Some(input),
+                raw_output: None,
+                meta: None,
+            })
+        };
+        if let Err(e) = client
+            .session_notification(SessionNotification {
+                session_id: session_id.clone(),
+                update,
+                meta: None,
+            })
+            .await
+        {
+            error!("Failed to send session notification: {:?}", e);
+        }
+
+        let exit_status = tokio::select! {
+            response = client.wait_for_terminal_exit(WaitForTerminalExitRequest {
+                session_id: session_id.clone(),
+                terminal_id: terminal_id.clone(),
+                meta: None,
+            }) => response.ok().map(|response| response.exit_status),
+            _ = cancelled.cancelled() => {
+                if let Err(e) = client
+                    .kill_terminal_command(KillTerminalCommandRequest {
+                        session_id: session_id.clone(),
+                        terminal_id: terminal_id.clone(),
+                        meta: None,
+                    })
+                    .await
+                {
+                    error!("Failed to kill terminal command: {:?}", e);
+                }
+                None
+            }
+        };
+
+        let output = client
+            .terminal_output(TerminalOutputRequest {
+                session_id: session_id.clone(),
+                terminal_id: terminal_id.clone(),
+                meta: None,
+            })
+            .await;
+        // The client keeps showing the output of a released terminal embedded in a tool call.
+        if let Err(e) = client
+            .release_terminal(ReleaseTerminalRequest {
+                session_id: session_id.clone(),
+                terminal_id,
+                meta: None,
+            })
+            .await
+        {
+            error!("Failed to release terminal: {:?}", e);
+        }
+
+        let output = output.map_err(|e| format!("Failed to read terminal output: {e}"))?;
+        Ok(terminal_result(
+            output,
+            exit_status,
+            cancelled.is_cancelled(),
+        ))
+    }
+
+    /// ACP wants absolute paths, so resolve relative ones against the session cwd.
+    fn session_path(&self, session_id: &SessionId, path: PathBuf) -> PathBuf {
+        if path.is_absolute() {
+            return path;
+        }
+        match (*self.sessions).borrow().get(session_id) {
+            Some(session) => session.cwd.join(path),
+            None => path,
+        }
+    }
+
+    async fn request_tool_permission(
+        &self,
+        session_id: &SessionId,
+        turn: &RefCell<TurnContext>,
+        tool: AmpTool,
+        input: serde_json::Value,
+    ) -> PermissionDecision {
+        let tool_use_id = Self::match_tool_use(&mut turn.borrow_mut(), &tool, &input);
+        let decision = self
+            .decide_tool_permission(session_id, turn, &tool_use_id, tool, input)
+            .await;
+        if decision == PermissionDecision::Allow {
+            self.mark_in_progress(session_id.clone(), turn, &tool_use_id)
+                .await;
+        }
+        decision
+    }
+
+    async fn decide_tool_permission(
+        &self,
+        session_id: &SessionId,
+        turn: &RefCell<TurnContext>,
+        tool_use_id: &str,
+        tool: AmpTool,
+        input: serde_json::Value,
+    ) -> PermissionDecision {
+        let scope = permissions::allow_scope(&tool, &input);
+        let Some((cwd, always_allowed)) = (*self.sessions)
+            .borrow()
+            .get(session_id)
+            .map(|session| (session.cwd.clone(), session.always_allowed.contains(&scope)))
+        else {
+            return PermissionDecision::Reject;
+        };
+
+        let tool_call =
+            permissions::permission_tool_call(ToolCallId(Arc::from(tool_use_id)), &tool, &input);
+
+        let verdict = self.policy.evaluate(&tool, &input, &cwd);
+        match verdict.as_ref().map(|v| v.action) {
+            Some(PolicyAction::Allow) => return PermissionDecision::Allow,
+            Some(PolicyAction::Deny) => {
+                let rule = verdict.map(|v| v.to_string()).unwrap_or_default();
+                self.report_denied_tool_call(session_id, turn, tool_call, tool_use_id, &rule)
+                    .await;
+                return PermissionDecision::Deny(rule);
+            }
+            // An explicit ask rule prompts even if the user said "always allow" before.
+            Some(PolicyAction::Ask) => {}
+            None => {
+                if always_allowed || !permissions::GATED_TOOLS.contains(&tool) {
+                    return PermissionDecision::Allow;
+                }
+            }
+        }
+
+        let request = RequestPermissionRequest {
+            session_id: session_id.clone(),
+            tool_call,
+            options: permissions::permission_options(&tool, &input),
+            meta: None,
+        };
+
+        let outcome = match self.client().request_permission(request).await {
+            Ok(response) => response.outcome,
+            Err(e) => {
+                error!("Failed to request permission: {:?}", e);
+                return PermissionDecision::Reject;
+            }
+        };
+
+        match outcome {
+            RequestPermissionOutcome::Selected { option_id } => {
+                let (decision, remember) = permissions::decision_for_option(&option_id);
+                if remember {
+                    if let Some(session) = (*self.sessions).borrow_mut().get_mut(session_id) {
+                        session.always_allowed.insert(scope);
+                    }
+                }
+                decision
+            }
+            RequestPermissionOutcome::Cancelled => PermissionDecision::Reject,
+        }
+    }
+
+    async fn report_denied_tool_call(
+        &self,
+        session_id: &SessionId,
+        turn: &RefCell<TurnContext>,
+        mut tool_call: ToolCallUpdate,
+        tool_use_id: &str,
+        rule: &str,
+    ) {
+        tool_call.fields.status = Some(ToolCallStatus::Failed);
+        tool_call.fields.content = Some(vec![ToolCallContent::Content {
+            content: ContentBlock::Text(TextContent {
+                annotations: None,
+                text: format!("Blocked by {rule}"),
+                meta: None,
+            }),
+        }]);
+
+        // A helper can get ahead of amp's stream, so the client may not know this call yet.
+        let announced = {
+            let mut turn = turn.borrow_mut();
+            turn.file_edits.remove(tool_use_id);
+            turn.denied_tool_calls.insert(tool_use_id.to_string());
+            turn.open_tool_calls.remove(tool_use_id).is_some()
+        };
+        let update = if announced {
+            SessionUpdate::ToolCallUpdate(tool_call)
+        } else {
+            match ToolCall::try_from(tool_call) {
+                Ok(tool_call) => SessionUpdate::ToolCall(tool_call),
+                Err(e) => {
+                    error!("Failed to build denied tool call: {:?}", e);
+                    return;
+                }
+            }
+        };
+
+        if let Err(e) = self
+            .client()
+            .session_notification(SessionNotification {
+                session_id: session_id.clone(),
+                update,
+                meta: None,
+            })
+            .await
+        {
+            error!("Failed to send session notification: {:?}", e);
+        }
+    }
+
+    async fn run_prompt(
+        &self,
+        request: PromptRequest,
+        turn: &RefCell<TurnContext>,
+    ) -> Result<PromptResponse, Error> {
+        let (mode, cwd, mcp_servers, cancelled) = (*self.sessions)
+            .borrow()
+            .get(&request.session_id)
+            .map(|session| {
+                (
+                    session.mode,
+                    session.cwd.clone(),
+                    session.mcp_servers.clone(),
+                    session.cancelled.clone(),
+                )
+            })
+            .ok_or_else(|| {
+                Error::invalid_params()
+                    .with_data(format!("Unknown session: {}", request.session_id.0))
+            })?;
+
+        // Lives until the end of the turn so amp can still read the files it references.
+        let mut attachments = TurnAttachments::new();
+        let prompt = tokio::select! {
+            prompt = async {
+                let mut prompt_builder =
+                    PromptBuilder::new(&cwd, &mut attachments, self.audio_handler.clone());
+                for block in &request.prompt {
+                    prompt_builder.push(block).await?;
+                }
+                Ok::<_, Error>(prompt_builder.build())
+            } => prompt?,
+            _ = cancelled.cancelled() => {
+                return Ok(PromptResponse {
+                    stop_reason: StopReason::Cancelled,
+                    meta: None,
+                });
+            }
+        };
+
+        let mut command = self.amp_command();
+        command
+            .current_dir(&cwd)
+            .args([
+                "threads",
+                "continue",
                 &request.session_id.0,
                 "-x",
                 "--stream-json",
             ])
+            .args(mode.cli_args());
+
+        let bridge = Bridge::bind();
+        if let Ok(bridge) = &bridge {
+            command.env(bridge::SOCKET_ENV, bridge.path());
+        }
+
+        let overlay = self.settings_overlay(mode, mcp_servers, bridge.as_ref())?;
+        let settings_path = {
+            let sessions = (*self.sessions).borrow();
+            let settings = &sessions
+                .get(&request.session_id)
+                .ok_or_else(|| Error::internal_error().with_data("Session disappeared"))?
+                .settings;
+            settings.write(overlay).map_err(|e| {
+                Error::internal_error().with_data(format!("Failed to prepare amp settings: {e}"))
+            })?;
+            settings.path().to_path_buf()
+        };
+        command.arg("--settings-file").arg(settings_path);
+
+        // A cancel that came in before amp exists had no process to stop, so don't start
+        // one. From here on there is no await until the child is stored for `cancel`.
+        if cancelled.is_cancelled() {
+            return Ok(PromptResponse {
+                stop_reason: StopReason::Cancelled,
+                meta: None,
+            });
+        }
+
+        let mut child = process::isolate_process_group(&mut command)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
+            .kill_on_drop(true)
             .spawn()
             .map_err(|_| Error::internal_error().with_data("Failed to start amp"))?;
 
-        if let Some(mut stdin) = child.stdin.take() {
-            stdin.write_all(prompt.as_bytes()).map_err(|e| {
-                Error::internal_error().with_data(format!("Failed to send prompt to amp: {e}"))
-            })?;
-        }
-
+        let stdin = child.stdin.take();
         let stdout = child
             .stdout
             .take()
             .ok_or_else(|| Error::internal_error().with_data("Failed to capture stdout"))?;
-
-        self.set_amp_command(child);
+        let stderr = process::StderrCapture::spawn(child.stderr.take());
 
         let session_id = request.session_id;
-        let reader = BufReader::new(stdout);
+        if let Some(session) = (*self.sessions).borrow_mut().get_mut(&session_id) {
+            session.child = Some(child);
+        }
 
-        let mut file_edits: HashMap<String, AmpEditFileToolCall> = HashMap::new();
+        if let Some(mut stdin) = stdin {
+            // A cancel may stop amp before it has read the prompt; that isn't a failure.
+            if let Err(e) = stdin.write_all(prompt.as_bytes()).await {
+                if !cancelled.is_cancelled() {
+                    return Err(Error::internal_error()
+                        .with_data(format!("Failed to send prompt to amp: {e}")));
+                }
+            }
+        }
 
-        for line in reader.lines() {
-            let line = line.map_err(|e| {
-                Error::internal_error().with_data(format!("Failed to read stdout: {e}"))
-            })?;
+        let mut lines = BufReader::new(stdout).lines();
+
+        let mut amp_result = None;
+        // Each helper connection is served alongside the stream, so a long Bash command or
+        // an open permission prompt doesn't hold up amp's other events and tools.
+        let mut connections = FuturesUnordered::new();
+
+        loop {
+            tokio::select! {
+                biased;
+                line = lines.next_line() => {
+                    let Some(line) = line.map_err(|e| {
+                        Error::internal_error().with_data(format!("Failed to read stdout: {e}"))
+                    })?
+                    else {
+                        break;
+                    };
 
-            if line.trim().is_empty() {
-                continue;
-            }
+                    if line.trim().is_empty() {
+                        continue;
+                    }
 
-            let event: AmpStreamEvent = match serde_json::from_str(&line) {
-                Ok(e) => e,
-                Err(e) => {
-                    error!("Failed to parse stream event: {e} (line: {line})");
-                    continue;
-                }
-            };
+                    let event: AmpStreamEvent = match serde_json::from_str(&line) {
+                        Ok(e) => e,
+                        Err(e) => {
+                            error!("Failed to parse stream event: {e} (line: {line})");
+                            continue;
+                        }
+                    };
 
-            match event {
-                AmpStreamEvent::System { .. } => {
-                    continue;
-                }
-                AmpStreamEvent::User {
-                    message,
-                    parent_tool_use_id,
-                    ..
-                } => {
-                    self.process_message(
-                        &message,
-                        session_id.clone(),
-                        &mut file_edits,
-                        parent_tool_use_id,
-                    )
-                    .await;
-                }
-                AmpStreamEvent::Assistant {
-                    message,
-                    parent_tool_use_id,
-                    ..
-                } => {
-                    if let Ok(amp_message) = serde_json::from_value::<AmpMessage>(message) {
-                        self.process_message(
-                            &amp_message,
-                            session_id.clone(),
-                            &mut file_edits,
-                            parent_tool_use_id,
-                        )
-                        .await;
+                    if let AmpStreamEvent::Result { result } = event {
+                        amp_result = Some(result);
+                        break;
                     }
+                    self.handle_stream_event(event, &session_id, turn).await;
                 }
-                AmpStreamEvent::Result { .. } => {
-                    break;
+                connection = bridge::accept(bridge.as_ref().ok()) => {
+                    match connection {
+                        Ok(connection) => {
+                            connections.push(
+                                self.handle_bridge_connection(connection, &session_id, turn),
+                            );
+                        }
+                        Err(e) => error!("Failed to accept bridge connection: {:?}", e),
+                    }
                 }
+                Some(()) = connections.next(), if !connections.is_empty() => {}
             }
         }
 
-        let status = (*self.amp_command)
+        // Let helpers that are still finishing up (a terminal being killed after a cancel,
+        // say) answer, but don't wait on them forever now that amp is done.
+        let drain = async { while connections.next().await.is_some() {} };
+        if tokio::time::timeout(self.cancel_grace, drain)
+            .await
+            .is_err()
+        {
+            error!("Gave up on bridge requests still open at the end of the turn");
+        }
+
+        // Take the child out of the slot so no RefCell borrow is held across the await.
+        let mut child = (*self.sessions)
             .borrow_mut()
-            .as_mut()
-            .ok_or_else(|| Error::internal_error().with_data("Amp process not found"))?
-            .wait()
-            .map_err(|e| {
-                Error::internal_error().with_data(format!("Failed to wait for amp process: {e}"))
-            })?;
+            .get_mut(&session_id)
+            .and_then(|session| session.child.take())
+            .ok_or_else(|| Error::internal_error().with_data("Amp process not found"))?;
+
+        let cancelled = (*self.sessions)
+            .borrow()
+            .get(&session_id)
+            .is_some_and(|session| session.turn == TurnState::Cancelled);
+
+        let status = if cancelled {
+            self.wait_for_interrupted(&mut child).await
+        } else {
+            child.wait().await
+        }
+        .map_err(|e| {
+            Error::internal_error().with_data(format!("Failed to wait for amp process: {e}"))
+        })?;
+
+        let meta = match &amp_result {
+            Some(result) => Some(self.record_usage(&session_id, result).await),
+            None => None,
+        };
+
+        if cancelled {
+            return Ok(PromptResponse {
+                stop_reason: StopReason::Cancelled,
+                meta,
+            });
+        }
+
+        // Amp exits non-zero when a run ends early, so its result says more than the status.
+        if let Some(result) = amp_result.filter(|result| result.failed()) {
+            let stderr = stderr.finish().await;
+            return result_stop_reason(&result, &stderr)
+                .map(|stop_reason| PromptResponse { stop_reason, meta });
+        }
 
         if !status.success() {
-            return Err(Error::internal_error()
-                .with_data(format!("Amp process exited with status: {status}")));
+            let stderr = stderr.finish().await;
+            if auth::is_auth_failure(&stderr) {
+                return Err(auth::auth_required(&stderr));
+            }
+            return Err(Error::internal_error().with_data(format!(
+                "Amp process exited with status: {status}: {}",
+                stderr.trim()
+            )));
         }
 
         Ok(PromptResponse {
             stop_reason: StopReason::EndTurn,
+            meta,
+        })
+    }
+
+    /// Adds a turn's usage to the session totals and reports both to the client, returning
+    /// them as the `_meta` of the prompt response.
+    async fn record_usage(&self, session_id: &SessionId, result: &AmpResult) -> serde_json::Value {
+        let usage = Usage::from(result);
+        let session_usage = (*self.sessions)
+            .borrow_mut()
+            .get_mut(session_id)
+            .map(|session| {
+                session.usage.add(&usage);
+                session.usage.clone()
+            })
+            .unwrap_or_else(|| usage.clone());
+
+        let meta = serde_json::json!({
+            "usage": usage,
+            "sessionUsage": session_usage,
+        });
+
+        let mut params = meta.clone();
+        params["sessionId"] = serde_json::json!(session_id);
+        match serde_json::value::to_raw_value(&params) {
+            Ok(params) => {
+                if let Err(e) = self
+                    .client()
+                    .ext_notification(ExtNotification {
+                        method: Arc::from(USAGE_NOTIFICATION),
+                        params: Arc::from(params),
+                    })
+                    .await
+                {
+                    error!("Failed to send usage notification: {:?}", e);
+                }
+            }
+            Err(e) => error!("Failed to serialize usage: {:?}", e),
+        }
+
+        meta
+    }
+}
+
+/// Why a turn that amp reported as failed stopped. Limits and refusals end the turn
+/// normally; anything else is an error carrying amp's message.
+fn result_stop_reason(result: &AmpResult, stderr: &str) -> Result<StopReason, Error> {
+    match result.subtype.as_str() {
+        "error_max_turns" => return Ok(StopReason::MaxTurnRequests),
+        "error_max_tokens" | "error_context_limit" => return Ok(StopReason::MaxTokens),
+        "error_refusal" => return Ok(StopReason::Refusal),
+        _ => {}
+    }
+
+    let message = result.error_message().unwrap_or_default();
+    let lowercase = message.to_lowercase();
+    if auth::is_auth_failure(&message) {
+        return Err(auth::auth_required(&message));
+    }
+    if [
+        "context window",
+        "context length",
+        "context limit",
+        "prompt is too long",
+        "max_tokens",
+    ]
+    .iter()
+    .any(|pattern| lowercase.contains(pattern))
+    {
+        return Ok(StopReason::MaxTokens);
+    }
+    if lowercase.contains("refusal") {
+        return Ok(StopReason::Refusal);
+    }
+    // Stderr also carries MCP server and tool noise, so it's only a last resort.
+    if auth::is_auth_failure(stderr) {
+        return Err(auth::auth_required(stderr));
+    }
+
+    Err(Error::internal_error().with_data(serde_json::json!({
+        "subtype": result.subtype,
+        "message": if message.is_empty() { "Amp reported an error" } else { message.trim() },
+        "stderr": stderr,
+    })))
+}
+
+const USAGE_NOTIFICATION: &str = "amp/usage";
+
+const TERMINAL_OUTPUT_LIMIT: u64 = 64 * 1024;
+
+fn terminal_result(
+    output: TerminalOutputResponse,
+    exit_status: Option<TerminalExitStatus>,
+    cancelled: bool,
+) -> String {
+    let mut result = output.output;
+    if output.truncated {
+        result.insert_str(0, "[earlier output truncated]\n");
+    }
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+
+    let status = if cancelled {
+        "Cancelled by the user".to_string()
+    } else {
+        match output.exit_status.or(exit_status) {
+            Some(TerminalExitStatus {
+                exit_code: Some(code),
+                ..
+            }) => format!("Exit code: {code}"),
+            Some(TerminalExitStatus {
+                signal: Some(signal),
+                ..
+            }) => format!("Terminated by signal {signal}"),
+            _ => "Exit status unknown".to_string(),
+        }
+    };
+    result.push_str(&status);
+    result
+}
+
+fn get_line_number_from_diff_str(diff: &str) -> Option<u32> {
+    let parts = diff.split("@@").collect::<Vec<&str>>();
+    let header = parts.get(1)?.trim();
+    let line_info_parts = header.split(" ").collect::<Vec<&str>>();
+    let final_line_number = line_info_parts.get(1)?;
+    let line_number_parts = final_line_number.split(",").collect::<Vec<&str>>();
+    let line_number = line_number_parts.first()?.replace("+", "").parse::<u32>();
+
+    line_number.ok()
+}
+
+#[async_trait::async_trait(?Send)]
+impl Agent for AmpAgent {
+    async fn initialize(&self, request: InitializeRequest) -> Result<InitializeResponse, Error> {
+        *self.client_capabilities.borrow_mut() = request.client_capabilities;
+
+        return Ok(InitializeResponse {
+            meta: None,
+            protocol_version: V1,
+            agent_capabilities: AgentCapabilities {
+                load_session: true,
+                prompt_capabilities: PromptCapabilities {
+                    image: true,
+                    audio: self.audio_handler.is_some(),
+                    embedded_context: true,
+                    meta: None,
+                },
+                mcp_capabilities: McpCapabilities {
+                    http: true,
+                    sse: true,
+                    meta: None,
+                },
+                meta: None,
+            },
+            auth_methods: auth::auth_methods(),
+        });
+    }
+
+    async fn authenticate(
+        &self,
+        request: AuthenticateRequest,
+    ) -> Result<AuthenticateResponse, Error> {
+        let api_key = self.api_key.borrow().clone();
+        match &*request.method_id.0 {
+            auth::API_KEY_METHOD => match ApiKey::from_meta(request.meta.as_ref()) {
+                // Only keep a key from the client once amp has accepted it.
+                Some(key) => {
+                    auth::verify(Some(&key)).await?;
+                    *self.api_key.borrow_mut() = Some(key);
+                }
+                None => auth::verify(api_key.as_ref()).await?,
+            },
+            auth::LOGIN_METHOD => auth::login(api_key.as_ref()).await?,
+            method => {
+                return Err(Error::invalid_params()
+                    .with_data(format!("Unknown authentication method: {method}")));
+            }
+        }
+        Ok(AuthenticateResponse { meta: None })
+    }
+
+    async fn new_session(&self, request: NewSessionRequest) -> Result<NewSessionResponse, Error> {
+        self.validate_cwd(&request.cwd)?;
+        self.check_amp_installed(&request.cwd).await?;
+
+        let output = self
+            .amp_command()
+            .current_dir(request.cwd.clone())
+            .args(["threads", "new"])
+            .output()
+            .await
+            .map_err(Error::into_internal_error)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if auth::is_auth_failure(&stderr) {
+                return Err(auth::auth_required(&stderr));
+            }
+            return Err(Error::internal_error()
+                .with_data(format!("Failed to create amp thread: {}", stderr.trim())));
+        }
+
+        let session_id = match String::from_utf8(output.stdout) {
+            Ok(s) => Some(s.replace("\n", "")),
+            Err(_) => None,
+        };
+
+        if let Some(session_id) = session_id {
+            let session_id = SessionId(Arc::from(session_id));
+            (*self.sessions).borrow_mut().insert(
+                session_id.clone(),
+                Session::new(
+                    &session_id,
+                    request.cwd,
+                    settings::mcp_servers(&request.mcp_servers),
+                ),
+            );
+
+            Ok(NewSessionResponse {
+                session_id,
+                modes: Some(mode::mode_state(AmpMode::default())),
+                meta: None,
+            })
+        } else {
+            Err(Error::internal_error())
+        }
+    }
+
+    async fn load_session(
+        &self,
+        request: LoadSessionRequest,
+    ) -> Result<LoadSessionResponse, Error> {
+        if (*self.sessions)
+            .borrow()
+            .get(&request.session_id)
+            .is_some_and(|session| session.turn != TurnState::Idle)
+        {
+            return Err(
+                Error::invalid_request().with_data("A prompt is already running for this session")
+            );
+        }
+
+        self.validate_cwd(&request.cwd)?;
+        self.check_amp_installed(&request.cwd).await?;
+
+        let conversation = self
+            .get_amp_thread(&request.session_id, &request.cwd)
+            .await?;
+
+        let turn = RefCell::new(TurnContext::default());
+        for message in &conversation.messages {
+            self.process_message(message, request.session_id.clone(), &turn, None)
+                .await;
+        }
+        // Tool calls the thread never saw a result for are not going to finish now.
+        self.fail_open_tool_calls(&request.session_id, &turn).await;
+
+        // A prompt may have started while the thread was replayed. Otherwise an already
+        // loaded session keeps its mode, usage and approvals, and only picks up the cwd and
+        // MCP servers from this request.
+        let mcp_servers = settings::mcp_servers(&request.mcp_servers);
+        let mode = match (*self.sessions)
+            .borrow_mut()
+            .entry(request.session_id.clone())
+        {
+            Entry::Occupied(entry) => {
+                let session = entry.into_mut();
+                if session.turn != TurnState::Idle {
+                    return Err(Error::invalid_request()
+                        .with_data("A prompt is already running for this session"));
+                }
+                session.cwd = request.cwd;
+                session.mcp_servers = mcp_servers;
+                session.mode
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Session::new(&request.session_id, request.cwd, mcp_servers));
+                AmpMode::default()
+            }
+        };
+
+        Ok(LoadSessionResponse {
+            modes: Some(mode::mode_state(mode)),
             meta: None,
         })
     }
 
-    async fn cancel(&self, _args: CancelNotification) -> Result<(), Error> {
-        let res = (*self.amp_command).borrow_mut().as_mut().unwrap().kill();
-        if res.is_err() {
-            return Err(Error::internal_error().with_data("Could not kill the amp process"));
+    async fn prompt(&self, request: PromptRequest) -> Result<PromptResponse, Error> {
+        let session_id = request.session_id.clone();
+        {
+            let mut sessions = (*self.sessions).borrow_mut();
+            let session = sessions.get_mut(&session_id).ok_or_else(|| {
+                Error::invalid_params().with_data(format!("Unknown session: {}", session_id.0))
+            })?;
+            if session.turn != TurnState::Idle {
+                return Err(Error::invalid_request()
+                    .with_data("A prompt is already running for this session"));
+            }
+            session.turn = TurnState::Running;
+            session.cancelled = CancellationToken::new();
+        }
+
+        let turn = RefCell::new(TurnContext::default());
+        let result = self.run_prompt(request, &turn).await;
+        // Whatever ended the turn, tool calls without a result won't get one now.
+        self.fail_open_tool_calls(&session_id, &turn).await;
+
+        if let Some(session) = (*self.sessions).borrow_mut().get_mut(&session_id) {
+            session.child = None;
+            session.turn = TurnState::Idle;
+        }
+
+        result
+    }
+
+    async fn cancel(&self, args: CancelNotification) -> Result<(), Error> {
+        let mut sessions = (*self.sessions).borrow_mut();
+        let Some(session) = sessions.get_mut(&args.session_id) else {
+            return Ok(());
+        };
+        if session.turn != TurnState::Running {
+            return Ok(());
+        }
+
+        session.turn = TurnState::Cancelled;
+        session.cancelled.cancel();
+        let Some(child) = session.child.as_mut() else {
+            return Ok(());
+        };
+
+        if let Err(e) = process::interrupt(child) {
+            error!("Could not interrupt the amp process: {:?}", e);
+        }
+
+        // If amp is still streaming once the grace period is over, kill its whole group.
+        // Once the stream ends the prompt takes the child and enforces the deadline itself.
+        if let Some(pgid) = child.id() {
+            let sessions = Rc::clone(&self.sessions);
+            let session_id = args.session_id;
+            let grace = self.cancel_grace;
+            tokio::task::spawn_local(async move {
+                tokio::time::sleep(grace).await;
+                let still_running = (*sessions)
+                    .borrow()
+                    .get(&session_id)
+                    .and_then(|session| session.child.as_ref())
+                    .is_some_and(|child| child.id() == Some(pgid));
+                if still_running {
+                    if let Err(e) = process::kill_group(pgid) {
+                        error!("Could not kill the amp process group: {:?}", e);
+                    }
+                }
+            });
         }
         Ok(())
     }
 
     async fn set_session_mode(
         &self,
-        _args: SetSessionModeRequest,
+        args: SetSessionModeRequest,
     ) -> Result<SetSessionModeResponse, Error> {
-        todo!()
+        let mode = AmpMode::from_id(&args.mode_id).ok_or_else(|| {
+            Error::invalid_params().with_data(format!("Unknown session mode: {}", args.mode_id))
+        })?;
+
+        (*self.sessions)
+            .borrow_mut()
+            .get_mut(&args.session_id)
+            .ok_or_else(|| {
+                Error::invalid_params().with_data(format!("Unknown session: {}", args.session_id.0))
+            })?
+            .mode = mode;
+
+        let notification = SessionNotification {
+            session_id: args.session_id,
+            update: SessionUpdate::CurrentModeUpdate {
+                current_mode_id: args.mode_id,
+            },
+            meta: None,
+        };
+
+        if let Err(e) = self.client().session_notification(notification).await {
+            error!("Failed to send session notification: {:?}", e);
+        }
+
+        Ok(SetSessionModeResponse { meta: None })
     }
 
     async fn ext_method(&self, _args: ExtRequest) -> Result<ExtResponse, Error> {
@@ -1094,3 +2117,75 @@ impl Agent for AmpAgent {
         Err(Error::method_not_found())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failed(subtype: &str, error: Option<&str>) -> AmpResult {
+        AmpResult {
+            subtype: subtype.to_string(),
+            is_error: true,
+            error: error.map(|e| serde_json::json!({ "message": e })),
+            ..AmpResult::default()
+        }
+    }
+
+    #[test]
+    fn result_subtypes_map_to_stop_reasons() {
+        for (subtype, expected) in [
+            ("error_max_turns", StopReason::MaxTurnRequests),
+            ("error_max_tokens", StopReason::MaxTokens),
+            ("error_context_limit", StopReason::MaxTokens),
+            ("error_refusal", StopReason::Refusal),
+        ] {
+            let reason = result_stop_reason(&failed(subtype, None), "").ok();
+            assert_eq!(reason, Some(expected), "{subtype}");
+        }
+    }
+
+    #[test]
+    fn result_messages_map_to_stop_reasons() {
+        for (message, expected) in [
+            ("Prompt is too long for the model", StopReason::MaxTokens),
+            ("Exceeded the context window", StopReason::MaxTokens),
+            ("The model returned a refusal", StopReason::Refusal),
+        ] {
+            let result = failed("error_during_execution", Some(message));
+            let reason = result_stop_reason(&result, "").ok();
+            assert_eq!(reason, Some(expected), "{message}");
+        }
+    }
+
+    #[test]
+    fn auth_failures_need_authentication() {
+        let auth_required = Error::auth_required().code;
+
+        let result = failed("error_during_execution", Some("Unauthorized"));
+        let error = result_stop_reason(&result, "").unwrap_err();
+        assert_eq!(error.code, auth_required);
+
+        // Stderr is only consulted when the message says nothing more specific.
+        let result = failed("error_during_execution", None);
+        let error = result_stop_reason(&result, "Error: not logged in").unwrap_err();
+        assert_eq!(error.code, auth_required);
+        let result = failed("error_during_execution", Some("context length exceeded"));
+        let reason = result_stop_reason(&result, "Error: not logged in").ok();
+        assert_eq!(reason, Some(StopReason::MaxTokens));
+    }
+
+    #[test]
+    fn other_failures_are_internal_errors() {
+        let result = failed("error_during_execution", Some("boom"));
+        let error = result_stop_reason(&result, "trace").unwrap_err();
+        assert_eq!(error.code, Error::internal_error().code);
+        assert_eq!(
+            error.data,
+            Some(serde_json::json!({
+                "subtype": "error_during_execution",
+                "message": "boom",
+                "stderr": "trace",
+            }))
+        );
+    }
+}
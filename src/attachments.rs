@@ -0,0 +1,86 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Files written for a single prompt turn so amp can read content that only exists in
+/// the ACP request, such as pasted screenshots. They live in a directory only the user can
+/// read, and everything is deleted when the turn ends.
+pub struct TurnAttachments {
+    dir: Option<PathBuf>,
+    count: usize,
+}
+
+impl TurnAttachments {
+    pub fn new() -> Self {
+        Self {
+            dir: None,
+            count: 0,
+        }
+    }
+
+    fn dir(&mut self) -> io::Result<&Path> {
+        if self.dir.is_none() {
+            let dir = std::env::temp_dir().join(format!("amp-acp-{:x}", rand::random::<u64>()));
+            let mut builder = std::fs::DirBuilder::new();
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::DirBuilderExt;
+                builder.mode(0o700);
+            }
+            builder.create(&dir)?;
+            self.dir = Some(dir);
+        }
+        Ok(self
+            .dir
+            .as_deref()
+            .expect("attachment dir was just created"))
+    }
+
+    pub fn write(&mut self, prefix: &str, extension: &str, bytes: &[u8]) -> io::Result<PathBuf> {
+        self.count += 1;
+        let file_name = format!("{prefix}-{}.{extension}", self.count);
+        let path = self.dir()?.join(file_name);
+        // Embedded resources can be anything the client had open, so the files themselves
+        // are private too, not just the directory holding them.
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&path)?.write_all(bytes)?;
+        Ok(path)
+    }
+}
+
+impl Drop for TurnAttachments {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+pub fn extension_for_mime(mime_type: &str) -> &'static str {
+    match mime_type {
+        "image/png" => "png",
+        "image/jpeg" | "image/jpg" => "jpg",
+        "image/gif" => "gif",
+        "image/webp" => "webp",
+        "image/svg+xml" => "svg",
+        "image/bmp" => "bmp",
+        "audio/wav" | "audio/x-wav" | "audio/wave" => "wav",
+        "audio/mpeg" | "audio/mp3" => "mp3",
+        "audio/ogg" => "ogg",
+        "audio/webm" => "webm",
+        "audio/mp4" | "audio/m4a" | "audio/x-m4a" => "m4a",
+        "audio/flac" => "flac",
+        "application/pdf" => "pdf",
+        "application/zip" => "zip",
+        "application/gzip" | "application/x-gzip" => "gz",
+        "application/x-tar" => "tar",
+        "application/x-7z-compressed" => "7z",
+        "text/plain" => "txt",
+        _ => "bin",
+    }
+}
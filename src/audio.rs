@@ -0,0 +1,64 @@
+use agent_client_protocol::Error;
+use std::path::Path;
+use std::rc::Rc;
+use tokio::process::Command;
+
+/// Turns audio prompt content into text amp can work with. Amp itself has no audio
+/// input, so audio is only advertised to clients when a handler is configured.
+#[async_trait::async_trait(?Send)]
+pub trait AudioHandler {
+    async fn transcribe(&self, audio_path: &Path, mime_type: &str) -> Result<String, Error>;
+}
+
+/// Runs an external command with the audio file path as its last argument and uses
+/// its stdout as the transcript.
+pub struct CommandTranscriber {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandTranscriber {
+    pub fn new(command_line: &str) -> Option<Self> {
+        let mut parts = command_line.split_whitespace().map(str::to_string);
+        let program = parts.next()?;
+        Some(Self {
+            program,
+            args: parts.collect(),
+        })
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl AudioHandler for CommandTranscriber {
+    async fn transcribe(&self, audio_path: &Path, mime_type: &str) -> Result<String, Error> {
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .arg(audio_path)
+            .env("AMP_ACP_AUDIO_MIME_TYPE", mime_type)
+            .output()
+            .await
+            .map_err(|e| {
+                Error::internal_error().with_data(format!(
+                    "Failed to run audio transcriber {}: {e}",
+                    self.program
+                ))
+            })?;
+
+        if !output.status.success() {
+            return Err(Error::internal_error().with_data(format!(
+                "Audio transcriber exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+/// The handler configured through `AMP_ACP_AUDIO_TRANSCRIBER`, if any.
+pub fn handler_from_env() -> Option<Rc<dyn AudioHandler>> {
+    let command_line = std::env::var("AMP_ACP_AUDIO_TRANSCRIBER").ok()?;
+    let transcriber = CommandTranscriber::new(&command_line)?;
+    Some(Rc::new(transcriber))
+}
@@ -0,0 +1,161 @@
+use agent_client_protocol::{AuthMethod, AuthMethodId, Error};
+use std::cell::RefCell;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+
+pub const API_KEY_METHOD: &str = "api-key";
+pub const LOGIN_METHOD: &str = "amp-login";
+
+const API_KEY_ENV: &str = "AMP_API_KEY";
+const API_KEY_META: &str = "apiKey";
+
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+pub fn auth_methods() -> Vec<AuthMethod> {
+    vec![
+        AuthMethod {
+            id: AuthMethodId(Arc::from(API_KEY_METHOD)),
+            name: "API Key".to_string(),
+            description: Some(
+                "Amp API key (pass it as _meta.apiKey, set the AMP_API_KEY env var or run \
+                 'amp login')"
+                    .to_string(),
+            ),
+            meta: None,
+        },
+        AuthMethod {
+            id: AuthMethodId(Arc::from(LOGIN_METHOD)),
+            name: "Log in with Amp".to_string(),
+            description: Some("Runs 'amp login', which signs you in in the browser".to_string()),
+            meta: None,
+        },
+    ]
+}
+
+/// An API key handed to us by the client. It only lives in memory and in the environment of
+/// the amp processes we start, and never shows up in `Debug` output.
+#[derive(Clone)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    /// The key from `_meta.apiKey` of an authenticate request, if there is one.
+    pub fn from_meta(meta: Option<&serde_json::Value>) -> Option<Self> {
+        let key = meta?.get(API_KEY_META)?.as_str()?.trim();
+        (!key.is_empty()).then(|| Self(key.to_string()))
+    }
+}
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ApiKey(<redacted>)")
+    }
+}
+
+/// Every amp invocation goes through here so a client-supplied key reaches all of them.
+pub fn amp_command(api_key: Option<&ApiKey>) -> Command {
+    let mut command = Command::new("amp");
+    if let Some(api_key) = api_key {
+        command.env(API_KEY_ENV, &api_key.0);
+    }
+    command
+}
+
+/// Whether amp's output says it couldn't authenticate, as opposed to failing otherwise.
+pub fn is_auth_failure(output: &str) -> bool {
+    let output = output.to_lowercase();
+    [
+        "not logged in",
+        "not authenticated",
+        "unauthorized",
+        "unauthenticated",
+        "invalid api key",
+        "api key is invalid",
+        "authentication failed",
+        "authentication required",
+        "amp login",
+    ]
+    .iter()
+    .any(|pattern| output.contains(pattern))
+}
+
+pub fn auth_required(detail: &str) -> Error {
+    Error::auth_required().with_data(format!("Amp could not authenticate: {}", detail.trim()))
+}
+
+/// Checks that amp accepts the credential it would use. Amp decides where its login lives,
+/// so only its own answer counts.
+pub async fn verify(api_key: Option<&ApiKey>) -> Result<(), Error> {
+    let output = amp_command(api_key)
+        .args(["threads", "list"])
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| Error::internal_error().with_data(format!("Failed to run amp: {e}")))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if is_auth_failure(&stderr) {
+            return Err(auth_required(&stderr));
+        }
+        // Anything else isn't a credential problem; the next amp call will report it.
+        tracing::warn!("Could not verify amp credentials: {}", stderr.trim());
+    }
+    Ok(())
+}
+
+/// Runs `amp login`, which opens the browser and waits for the user to sign in. Its output
+/// goes to our stderr as it arrives, where clients show agent logs, since without a browser
+/// the URL amp prints is the only way in. It's also included in the error if the login
+/// doesn't succeed.
+pub async fn login(api_key: Option<&ApiKey>) -> Result<(), Error> {
+    let mut child = amp_command(api_key)
+        .arg("login")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::internal_error().with_data(format!("Failed to run amp login: {e}")))?;
+
+    let output = RefCell::new(Vec::new());
+    let run = async {
+        tokio::join!(
+            log_lines(child.stdout.take(), &output),
+            log_lines(child.stderr.take(), &output),
+        );
+        child.wait().await
+    };
+    let status = tokio::time::timeout(LOGIN_TIMEOUT, run).await;
+    let output = output.into_inner().join("\n");
+
+    let status = status
+        .map_err(|_| auth_required(&format!("'amp login' timed out: {output}")))?
+        .map_err(|e| Error::internal_error().with_data(format!("amp login failed: {e}")))?;
+    if !status.success() {
+        return Err(auth_required(&format!("'amp login' failed: {output}")));
+    }
+    verify(api_key).await
+}
+
+async fn log_lines(stream: Option<impl AsyncRead + Unpin>, output: &RefCell<Vec<String>>) {
+    let Some(stream) = stream else {
+        return;
+    };
+    let mut reader = BufReader::new(stream);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+        if !line.is_empty() {
+            eprintln!("amp login: {line}");
+            output.borrow_mut().push(line);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! A per-turn Unix socket that lets helper processes started by amp (the permission
+//! delegate and the MCP server) call back into the adapter, which owns the ACP connection.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::amp_agent::AmpTool;
+
+pub const SOCKET_ENV: &str = "AMP_ACP_BRIDGE_SOCKET";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BridgeRequest {
+    Permission {
+        tool: AmpTool,
+        input: serde_json::Value,
+    },
+    ReadTextFile {
+        path: PathBuf,
+        line: Option<u32>,
+        limit: Option<u32>,
+    },
+    WriteTextFile {
+        path: PathBuf,
+        content: String,
+    },
+    Bash {
+        input: serde_json::Value,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeResponse {
+    Ok(serde_json::Value),
+    Error(String),
+}
+
+#[cfg(unix)]
+pub struct Bridge {
+    path: PathBuf,
+    listener: tokio::net::UnixListener,
+}
+
+#[cfg(unix)]
+impl Bridge {
+    pub fn bind() -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("amp-acp-{:x}.sock", rand::random::<u64>()));
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        Ok(Self { path, listener })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn accept(&self) -> io::Result<BridgeConnection> {
+        let (stream, _) = self.listener.accept().await?;
+        Ok(BridgeConnection { stream })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Bridge;
+
+#[cfg(not(unix))]
+impl Bridge {
+    pub fn bind() -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the amp-acp bridge needs unix sockets",
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        Path::new("")
+    }
+
+    pub async fn accept(&self) -> io::Result<BridgeConnection> {
+        std::future::pending().await
+    }
+}
+
+/// Waits for the next helper connection, or forever when no bridge could be bound.
+pub async fn accept(bridge: Option<&Bridge>) -> io::Result<BridgeConnection> {
+    match bridge {
+        Some(bridge) => bridge.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub struct BridgeConnection {
+    #[cfg(unix)]
+    stream: tokio::net::UnixStream,
+}
+
+impl BridgeConnection {
+    #[cfg(unix)]
+    pub async fn read_request(&mut self) -> io::Result<BridgeRequest> {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut line = String::new();
+        tokio::io::BufReader::new(&mut self.stream)
+            .read_line(&mut line)
+            .await?;
+        serde_json::from_str(&line).map_err(io::Error::other)
+    }
+
+    #[cfg(unix)]
+    pub async fn respond(mut self, response: &BridgeResponse) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut line = serde_json::to_string(response)?;
+        line.push('\n');
+        self.stream.write_all(line.as_bytes()).await
+    }
+
+    #[cfg(not(unix))]
+    pub async fn read_request(&mut self) -> io::Result<BridgeRequest> {
+        std::future::pending().await
+    }
+
+    #[cfg(not(unix))]
+    pub async fn respond(self, _response: &BridgeResponse) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Sends a single request from a helper process and blocks until the adapter answers.
+#[cfg(unix)]
+pub fn request(request: &BridgeRequest) -> io::Result<BridgeResponse> {
+    use std::io::{BufRead, Write};
+
+    let socket = std::env::var_os(SOCKET_ENV)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "bridge socket is not set"))?;
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let mut response = String::new();
+    io::BufReader::new(stream).read_line(&mut response)?;
+    serde_json::from_str(&response).map_err(io::Error::other)
+}
+
+#[cfg(not(unix))]
+pub fn request(_request: &BridgeRequest) -> io::Result<BridgeResponse> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the amp-acp bridge needs unix sockets",
+    ))
+}
@@ -5,14 +5,36 @@ use tokio::{io, task::LocalSet};
 use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
 mod amp_agent;
+mod attachments;
+mod audio;
+mod auth;
+mod bridge;
+mod mcp_server;
+mod mode;
+mod permissions;
+mod policy;
+mod process;
+mod prompt;
+mod session;
+mod settings;
+mod tool_result;
+mod usage;
 use amp_agent::AmpAgent;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
+    if permissions::is_delegate_invocation() {
+        std::process::exit(permissions::run_delegate());
+    }
+    if mcp_server::is_server_invocation() {
+        std::process::exit(mcp_server::run());
+    }
+
     let stdin = tokio::io::stdin().compat();
     let stdout = tokio::io::stdout().compat_write();
 
-    let amp_agent = Rc::new(AmpAgent::new());
+    let policy = policy::Policy::from_env()?;
+    let amp_agent = Rc::new(AmpAgent::new(policy));
 
     LocalSet::new()
         .run_until(async move {
@@ -22,9 +44,11 @@ async fn main() -> io::Result<()> {
                 });
 
             amp_agent.set_client(Rc::new(client));
-            io_task
+            let result = io_task
                 .await
-                .map_err(|e| std::io::Error::other(format!("ACP I/O error: {e}")))
+                .map_err(|e| std::io::Error::other(format!("ACP I/O error: {e}")));
+            amp_agent.shutdown();
+            result
         })
         .await?;
 
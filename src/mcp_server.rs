@@ -0,0 +1,313 @@
+//! A minimal MCP stdio server that amp starts from the per-turn settings. It provides
+//! replacements for amp's builtin file and Bash tools under the same names and forwards
+//! the work over the bridge to the adapter, which performs it through the ACP client.
+
+use serde_json::{json, Value};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::amp_agent::{AmpCreateToolInput, AmpEditFileToolCall, AmpReadToolInput, AmpTool};
+use crate::bridge::{self, BridgeRequest, BridgeResponse};
+
+pub const SUBCOMMAND: &str = "mcp-server";
+pub const SERVER_NAME: &str = "amp-acp";
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+pub fn is_server_invocation() -> bool {
+    std::env::args().nth(1).as_deref() == Some(SUBCOMMAND)
+}
+
+pub fn tool_name(tool: &AmpTool) -> String {
+    serde_json::to_value(tool)
+        .ok()
+        .and_then(|name| name.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// The `amp.mcpServers` entry that starts this binary as a server for `tools`.
+pub fn server_config(exe: &Path, socket: &Path, tools: &[AmpTool]) -> Value {
+    let mut args = vec![SUBCOMMAND.to_string()];
+    args.extend(tools.iter().map(tool_name));
+    json!({
+        "command": exe,
+        "args": args,
+        "env": { bridge::SOCKET_ENV: socket },
+    })
+}
+
+/// Serves MCP requests on stdin until amp closes it. The tools to offer are passed as
+/// arguments after the subcommand.
+pub fn run() -> i32 {
+    let tools: Vec<AmpTool> = std::env::args()
+        .skip(2)
+        .filter_map(|name| serde_json::from_value(Value::String(name)).ok())
+        .filter(|tool| *tool != AmpTool::Other)
+        .collect();
+
+    let mut stdout = std::io::stdout().lock();
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let message: Value = match serde_json::from_str(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Invalid MCP message: {e}");
+                continue;
+            }
+        };
+        // Notifications such as `notifications/initialized` need no answer.
+        let Some(id) = message.get("id").cloned() else {
+            continue;
+        };
+        let method = message
+            .get("method")
+            .and_then(|m| m.as_str())
+            .unwrap_or_default();
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+        let response = match handle_request(method, params, &tools) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        };
+        if writeln!(stdout, "{response}")
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            break;
+        }
+    }
+    0
+}
+
+fn handle_request(method: &str, params: Value, tools: &[AmpTool]) -> Result<Value, (i64, String)> {
+    match method {
+        "initialize" => Ok(json!({
+            "protocolVersion": params
+                .get("protocolVersion")
+                .cloned()
+                .unwrap_or_else(|| json!("2024-11-05")),
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": SERVER_NAME, "version": env!("CARGO_PKG_VERSION") },
+        })),
+        "ping" => Ok(json!({})),
+        "tools/list" => Ok(json!({
+            "tools": tools.iter().filter_map(tool_definition).collect::<Vec<_>>(),
+        })),
+        "tools/call" => {
+            let name = params.get("name").cloned().unwrap_or(Value::Null);
+            let tool = serde_json::from_value::<AmpTool>(name)
+                .ok()
+                .filter(|tool| tools.contains(tool))
+                .ok_or_else(|| (INVALID_PARAMS, "Unknown tool".to_string()))?;
+            let arguments = params.get("arguments").cloned().unwrap_or(json!({}));
+
+            Ok(match call_tool(&tool, arguments) {
+                Ok(text) => json!({ "content": [{ "type": "text", "text": text }] }),
+                Err(message) => json!({
+                    "content": [{ "type": "text", "text": message }],
+                    "isError": true,
+                }),
+            })
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("Method not found: {method}"))),
+    }
+}
+
+fn tool_definition(tool: &AmpTool) -> Option<Value> {
+    let (description, input_schema) = match tool {
+        AmpTool::Read => (
+            "Read a file, including unsaved changes open in the user's editor. Returns the \
+             contents with line numbers. Directories are listed instead.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Absolute path to read" },
+                    "read_range": {
+                        "type": "array",
+                        "items": { "type": "number" },
+                        "minItems": 2,
+                        "maxItems": 2,
+                        "description": "Optional 1-based [start, end] line range",
+                    },
+                },
+                "required": ["path"],
+            }),
+        ),
+        AmpTool::EditFile => (
+            "Edit a file by replacing old_str with new_str. old_str must match exactly once \
+             unless replace_all is set. The change is made through the user's editor.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Absolute path to edit" },
+                    "old_str": { "type": "string", "description": "Text to replace" },
+                    "new_str": { "type": "string", "description": "Replacement text" },
+                    "replace_all": {
+                        "type": "boolean",
+                        "description": "Replace every occurrence of old_str",
+                    },
+                },
+                "required": ["path", "old_str", "new_str"],
+            }),
+        ),
+        AmpTool::CreateFile => (
+            "Create a file, or overwrite it if it exists. The file is written through the \
+             user's editor.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Absolute path to write" },
+                    "content": { "type": "string", "description": "The file contents" },
+                },
+                "required": ["path", "content"],
+            }),
+        ),
+        AmpTool::Bash => (
+            "Run a shell command with bash in a terminal the user can watch and stop. \
+             Returns the output and exit status.",
+            json!({
+                "type": "object",
+                "properties": {
+                    "cmd": { "type": "string", "description": "The command to run" },
+                    "cwd": {
+                        "type": "string",
+                        "description": "Absolute working directory, defaults to the workspace",
+                    },
+                },
+                "required": ["cmd"],
+            }),
+        ),
+        _ => return None,
+    };
+
+    Some(json!({
+        "name": tool_name(tool),
+        "description": description,
+        "inputSchema": input_schema,
+    }))
+}
+
+fn call_tool(tool: &AmpTool, arguments: Value) -> Result<String, String> {
+    let invalid = |e: serde_json::Error| format!("Invalid arguments: {e}");
+    match tool {
+        AmpTool::Read => read(serde_json::from_value(arguments).map_err(invalid)?),
+        AmpTool::EditFile => {
+            let replace_all = arguments
+                .get("replace_all")
+                .and_then(|r| r.as_bool())
+                .unwrap_or(false);
+            edit(
+                serde_json::from_value(arguments).map_err(invalid)?,
+                replace_all,
+            )
+        }
+        AmpTool::CreateFile => create(serde_json::from_value(arguments).map_err(invalid)?),
+        AmpTool::Bash => {
+            let output = bridge_call(BridgeRequest::Bash { input: arguments })?;
+            Ok(output.as_str().unwrap_or_default().to_string())
+        }
+        _ => Err(format!("{tool} is not provided by this server")),
+    }
+}
+
+fn read(input: AmpReadToolInput) -> Result<String, String> {
+    let path = PathBuf::from(&input.path);
+    if path.is_dir() {
+        return list_dir(&path);
+    }
+
+    let (line, limit) = match input.read_range.as_deref() {
+        Some([start, end]) => {
+            let start = (*start).max(1) as u32;
+            let end = (*end).max(0) as u32;
+            (Some(start), Some(end.saturating_sub(start) + 1))
+        }
+        _ => (None, None),
+    };
+
+    let content = bridge_call(BridgeRequest::ReadTextFile { path, line, limit })?;
+    let content = content.as_str().unwrap_or_default();
+    let first = line.unwrap_or(1) as usize;
+    Ok(content
+        .lines()
+        .enumerate()
+        .map(|(i, text)| format!("{}: {text}", first + i))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+fn list_dir(path: &Path) -> Result<String, String> {
+    let entries =
+        std::fs::read_dir(path).map_err(|e| format!("Failed to list {}: {e}", path.display()))?;
+    let mut names: Vec<String> = entries
+        .filter_map(Result::ok)
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                format!("{name}/")
+            } else {
+                name
+            }
+        })
+        .collect();
+    names.sort();
+    Ok(names.join("\n"))
+}
+
+fn edit(input: AmpEditFileToolCall, replace_all: bool) -> Result<String, String> {
+    let path = PathBuf::from(&input.path);
+    let old_str = input.old_str.unwrap_or_default();
+    if old_str.is_empty() {
+        return Err("old_str must not be empty; use create_file to write a whole file".into());
+    }
+
+    let content = bridge_call(BridgeRequest::ReadTextFile {
+        path: path.clone(),
+        line: None,
+        limit: None,
+    })?;
+    let content = content.as_str().unwrap_or_default();
+
+    let matches = content.matches(&old_str).count();
+    let content = match matches {
+        0 => return Err(format!("old_str was not found in {}", input.path)),
+        1 => content.replacen(&old_str, &input.new_str, 1),
+        _ if replace_all => content.replace(&old_str, &input.new_str),
+        n => {
+            return Err(format!(
+                "old_str matches {n} times in {}; include more context or set replace_all",
+                input.path
+            ))
+        }
+    };
+
+    bridge_call(BridgeRequest::WriteTextFile { path, content })?;
+    Ok(format!("Edited {}", input.path))
+}
+
+fn create(input: AmpCreateToolInput) -> Result<String, String> {
+    bridge_call(BridgeRequest::WriteTextFile {
+        path: PathBuf::from(&input.path),
+        content: input.content,
+    })?;
+    Ok(format!("Wrote {}", input.path))
+}
+
+fn bridge_call(request: BridgeRequest) -> Result<Value, String> {
+    match bridge::request(&request) {
+        Ok(BridgeResponse::Ok(value)) => Ok(value),
+        Ok(BridgeResponse::Error(e)) => Err(e),
+        Err(e) => Err(format!("Could not reach amp-acp: {e}")),
+    }
+}
@@ -0,0 +1,67 @@
+use agent_client_protocol::{SessionMode, SessionModeId, SessionModeState};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AmpMode {
+    #[default]
+    Smart,
+    Rush,
+    Plan,
+}
+
+impl AmpMode {
+    pub const ALL: [AmpMode; 3] = [AmpMode::Smart, AmpMode::Rush, AmpMode::Plan];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            AmpMode::Smart => "smart",
+            AmpMode::Rush => "rush",
+            AmpMode::Plan => "plan",
+        }
+    }
+
+    pub fn from_id(id: &SessionModeId) -> Option<AmpMode> {
+        Self::ALL.into_iter().find(|mode| mode.id() == &*id.0)
+    }
+
+    pub fn to_acp_mode(self) -> SessionMode {
+        let (name, description) = match self {
+            AmpMode::Smart => ("Smart", "Amp's default mode, using the most capable models"),
+            AmpMode::Rush => ("Rush", "Faster and cheaper, for small well-defined tasks"),
+            AmpMode::Plan => (
+                "Plan",
+                "Read-only: Amp can explore and plan but not edit or run commands",
+            ),
+        };
+        SessionMode {
+            id: SessionModeId(Arc::from(self.id())),
+            name: name.to_string(),
+            description: Some(description.to_string()),
+            meta: None,
+        }
+    }
+
+    /// Flags passed to `amp threads continue` for this mode.
+    pub fn cli_args(&self) -> [&'static str; 2] {
+        match self {
+            AmpMode::Smart | AmpMode::Plan => ["--mode", "smart"],
+            AmpMode::Rush => ["--mode", "rush"],
+        }
+    }
+
+    /// Tools amp must not use while in this mode.
+    pub fn disabled_tools(&self) -> &'static [&'static str] {
+        match self {
+            AmpMode::Smart | AmpMode::Rush => &[],
+            AmpMode::Plan => &["Bash", "create_file", "edit_file", "undo_edit"],
+        }
+    }
+}
+
+pub fn mode_state(current: AmpMode) -> SessionModeState {
+    SessionModeState {
+        current_mode_id: SessionModeId(Arc::from(current.id())),
+        available_modes: AmpMode::ALL.into_iter().map(AmpMode::to_acp_mode).collect(),
+        meta: None,
+    }
+}
@@ -0,0 +1,237 @@
+//! Routes amp's tool approvals to the ACP client.
+//!
+//! Amp is configured to delegate approval of destructive tools to this binary. When amp
+//! runs it, `AGENT_TOOL_NAME` holds the tool and stdin its input; we forward both over the
+//! bridge to the adapter, which asks the user via `session/request_permission`, and report
+//! the answer back to amp through our exit code.
+
+use agent_client_protocol::{
+    ContentBlock, Diff, PermissionOption, PermissionOptionId, PermissionOptionKind, TextContent,
+    ToolCallContent, ToolCallId, ToolCallLocation, ToolCallUpdate, ToolCallUpdateFields,
+};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::amp_agent::{
+    amp_tool_to_tool_kind, AmpBashToolInput, AmpCreateToolInput, AmpEditFileToolCall, AmpTool,
+};
+use crate::bridge::{self, BridgeRequest, BridgeResponse};
+
+pub const TOOL_NAME_ENV: &str = "AGENT_TOOL_NAME";
+
+pub const GATED_TOOLS: [AmpTool; 4] = [
+    AmpTool::Bash,
+    AmpTool::EditFile,
+    AmpTool::CreateFile,
+    AmpTool::UndoEdit,
+];
+
+const ALLOW_ONCE: &str = "allow_once";
+const ALLOW_ALWAYS: &str = "allow_always";
+const REJECT: &str = "reject";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PermissionDecision {
+    Allow,
+    Reject,
+    /// Blocked by the permission policy, with the rule that did it.
+    Deny(String),
+}
+
+/// Whether this process was started by amp as a permission delegate.
+pub fn is_delegate_invocation() -> bool {
+    std::env::var_os(TOOL_NAME_ENV).is_some() && std::env::var_os(bridge::SOCKET_ENV).is_some()
+}
+
+/// Entry point of the delegate. Amp treats exit code 0 as allow and 2 as reject.
+pub fn run_delegate() -> i32 {
+    let tool_name = std::env::var(TOOL_NAME_ENV).unwrap_or_default();
+    let tool =
+        serde_json::from_value(serde_json::Value::String(tool_name)).unwrap_or(AmpTool::Other);
+
+    let mut input = String::new();
+    let _ = std::io::stdin().read_to_string(&mut input);
+    let input = serde_json::from_str(&input).unwrap_or(serde_json::Value::Null);
+
+    match bridge::request(&BridgeRequest::Permission { tool, input }) {
+        Ok(BridgeResponse::Ok(decision)) => match serde_json::from_value(decision) {
+            Ok(PermissionDecision::Allow) => 0,
+            Ok(PermissionDecision::Deny(rule)) => {
+                eprintln!("Blocked by {rule}");
+                2
+            }
+            _ => {
+                eprintln!("The user rejected this tool call");
+                2
+            }
+        },
+        Ok(BridgeResponse::Error(e)) => {
+            eprintln!("Permission request failed: {e}");
+            2
+        }
+        Err(e) => {
+            eprintln!("Could not reach amp-acp to request permission: {e}");
+            2
+        }
+    }
+}
+
+/// `amp.permissions` rules that hand every gated tool, plus any the policy has rules
+/// for, to this binary.
+pub fn delegate_rules(delegate: &std::path::Path, policy_tools: &[AmpTool]) -> serde_json::Value {
+    let extra = policy_tools
+        .iter()
+        .filter(|tool| !GATED_TOOLS.contains(tool));
+    GATED_TOOLS
+        .iter()
+        .chain(extra)
+        .map(|tool| {
+            serde_json::json!({
+                "tool": tool,
+                "action": "delegate",
+                "to": delegate,
+            })
+        })
+        .collect()
+}
+
+/// What an "Always allow" answer covers for the rest of the session: the exact command for
+/// Bash, the file for edits, and the whole tool otherwise.
+pub fn allow_scope(tool: &AmpTool, input: &serde_json::Value) -> (AmpTool, Option<String>) {
+    let key = match tool {
+        AmpTool::Bash => "cmd",
+        AmpTool::EditFile | AmpTool::CreateFile | AmpTool::UndoEdit => "path",
+        _ => return (tool.clone(), None),
+    };
+    let target = input.get(key).and_then(|v| v.as_str()).map(str::to_string);
+    (tool.clone(), target)
+}
+
+pub fn permission_options(tool: &AmpTool, input: &serde_json::Value) -> Vec<PermissionOption> {
+    let always = match allow_scope(tool, input) {
+        (AmpTool::Bash, Some(cmd)) => format!("Always allow `{cmd}` this session"),
+        (_, Some(path)) => format!("Always allow changes to {path} this session"),
+        (tool, None) => {
+            let name = serde_json::to_value(&tool).unwrap_or_default();
+            format!(
+                "Always allow every {} call this session",
+                name.as_str().unwrap_or("tool")
+            )
+        }
+    };
+    vec![
+        PermissionOption {
+            id: PermissionOptionId(Arc::from(ALLOW_ONCE)),
+            name: "Allow".to_string(),
+            kind: PermissionOptionKind::AllowOnce,
+            meta: None,
+        },
+        PermissionOption {
+            id: PermissionOptionId(Arc::from(ALLOW_ALWAYS)),
+            name: always,
+            kind: PermissionOptionKind::AllowAlways,
+            meta: None,
+        },
+        PermissionOption {
+            id: PermissionOptionId(Arc::from(REJECT)),
+            name: "Reject".to_string(),
+            kind: PermissionOptionKind::RejectOnce,
+            meta: None,
+        },
+    ]
+}
+
+/// Maps the selected option to a decision and whether it should be remembered.
+pub fn decision_for_option(option_id: &PermissionOptionId) -> (PermissionDecision, bool) {
+    match &*option_id.0 {
+        ALLOW_ONCE => (PermissionDecision::Allow, false),
+        ALLOW_ALWAYS => (PermissionDecision::Allow, true),
+        _ => (PermissionDecision::Reject, false),
+    }
+}
+
+/// The tool call shown alongside the permission prompt.
+pub fn permission_tool_call(
+    id: ToolCallId,
+    tool: &AmpTool,
+    input: &serde_json::Value,
+) -> ToolCallUpdate {
+    let mut title = tool.to_string();
+    let mut content = None;
+    let mut locations = None;
+
+    match tool {
+        AmpTool::Bash => {
+            if let Ok(t) = serde_json::from_value::<AmpBashToolInput>(input.clone()) {
+                title = format!("Run `{}`", t.cmd);
+            }
+        }
+        AmpTool::EditFile => {
+            if let Ok(t) = serde_json::from_value::<AmpEditFileToolCall>(input.clone()) {
+                title = format!("Edit {}", t.path);
+                locations = Some(vec![ToolCallLocation {
+                    path: PathBuf::from(&t.path),
+                    line: None,
+                    meta: None,
+                }]);
+                content = Some(vec![ToolCallContent::Diff {
+                    diff: Diff {
+                        path: PathBuf::from(t.path),
+                        old_text: t.old_str,
+                        new_text: t.new_str,
+                        meta: None,
+                    },
+                }]);
+            }
+        }
+        AmpTool::CreateFile => {
+            if let Ok(t) = serde_json::from_value::<AmpCreateToolInput>(input.clone()) {
+                title = format!("Create {}", t.path);
+                locations = Some(vec![ToolCallLocation {
+                    path: PathBuf::from(&t.path),
+                    line: None,
+                    meta: None,
+                }]);
+                content = Some(vec![ToolCallContent::Diff {
+                    diff: Diff {
+                        path: PathBuf::from(t.path),
+                        old_text: None,
+                        new_text: t.content,
+                        meta: None,
+                    },
+                }]);
+            }
+        }
+        AmpTool::UndoEdit => {
+            if let Some(path) = input.get("path").and_then(|p| p.as_str()) {
+                title = format!("Undo last edit of {path}");
+            }
+        }
+        _ => {
+            content = Some(vec![ToolCallContent::Content {
+                content: ContentBlock::Text(TextContent {
+                    annotations: None,
+                    text: input.to_string(),
+                    meta: None,
+                }),
+            }]);
+        }
+    }
+
+    ToolCallUpdate {
+        id,
+        fields: ToolCallUpdateFields {
+            kind: Some(amp_tool_to_tool_kind(tool)),
+            status: None,
+            title: Some(title),
+            content,
+            locations,
+            raw_input: Some(input.clone()),
+            raw_output: None,
+        },
+        meta: None,
+    }
+}
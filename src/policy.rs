@@ -0,0 +1,578 @@
+//! Declarative allow/deny/ask rules evaluated before amp runs a tool.
+//!
+//! The policy is a JSON file named by `AMP_ACP_POLICY_FILE` (see the README for an
+//! example). The first rule matching a tool call decides it. Bash commands are split into
+//! the simple commands they run (across `&&`, `||`, `;`, `|`, `&`, newlines, subshells,
+//! groups and command substitutions) and every part is checked on its own, so the strictest
+//! verdict wins. A command we can't split with confidence, or one that hands another
+//! command to a wrapper like `sh -c` or `env`, is asked about. Paths are matched
+//! relative to the session cwd, or as absolute paths when they are outside of it.
+
+use serde::Deserialize;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::amp_agent::AmpTool;
+
+pub const POLICY_ENV: &str = "AMP_ACP_POLICY_FILE";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PolicyAction {
+    Allow,
+    Ask,
+    Deny,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    pub tool: AmpTool,
+    pub action: PolicyAction,
+    pub command: Option<String>,
+    pub path: Option<String>,
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    rules: Vec<PolicyRule>,
+}
+
+/// What decided a tool call: a rule, with its 1-based position in the policy file, or no
+/// rule when a Bash command was too complex to check.
+pub struct PolicyMatch<'a> {
+    pub action: PolicyAction,
+    pub rule: Option<(usize, &'a PolicyRule)>,
+}
+
+impl std::fmt::Display for PolicyMatch<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Some((index, rule)) = self.rule else {
+            return write!(
+                f,
+                "policy (the command is too complex to check against the rules)"
+            );
+        };
+        let action = match rule.action {
+            PolicyAction::Allow => "allow",
+            PolicyAction::Ask => "ask",
+            PolicyAction::Deny => "deny",
+        };
+        let tool = serde_json::to_value(&rule.tool).unwrap_or_default();
+        write!(
+            f,
+            "policy rule #{index} ({action} {}",
+            tool.as_str().unwrap_or("?")
+        )?;
+        if let Some(command) = &rule.command {
+            write!(f, " command \"{command}\"")?;
+        }
+        if let Some(path) = &rule.path {
+            write!(f, " path \"{path}\"")?;
+        }
+        write!(f, ")")?;
+        if let Some(reason) = &rule.reason {
+            write!(f, ": {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Policy {
+    /// Loads the policy named by `AMP_ACP_POLICY_FILE`. No file means no rules, but a file
+    /// that can't be read or parsed is an error rather than silently allowing everything.
+    pub fn from_env() -> io::Result<Self> {
+        match std::env::var_os(POLICY_ENV) {
+            Some(path) => Self::load(Path::new(&path)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid policy file {}: {message}", path.display()),
+            )
+        };
+
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read policy file {}: {e}", path.display()),
+            )
+        })?;
+        let policy: Self = serde_json::from_str(&contents).map_err(|e| invalid(e.to_string()))?;
+
+        for (i, rule) in policy.rules.iter().enumerate() {
+            if rule.tool == AmpTool::Other {
+                return Err(invalid(format!("rule #{} names an unknown tool", i + 1)));
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Tools the policy has rules for, which amp has to ask us about before running them.
+    pub fn tools(&self) -> Vec<AmpTool> {
+        let mut tools: Vec<AmpTool> = Vec::new();
+        for rule in &self.rules {
+            if !tools.contains(&rule.tool) {
+                tools.push(rule.tool.clone());
+            }
+        }
+        tools
+    }
+
+    /// The rule deciding this tool call, or `None` when no rule covers it and the default
+    /// behaviour applies.
+    pub fn evaluate(
+        &self,
+        tool: &AmpTool,
+        input: &serde_json::Value,
+        cwd: &Path,
+    ) -> Option<PolicyMatch<'_>> {
+        let path = input
+            .get("path")
+            .and_then(|p| p.as_str())
+            .map(|p| relative_to(cwd, p));
+        let (commands, clean) = match input.get("cmd").and_then(|c| c.as_str()) {
+            Some(cmd) => {
+                let (parts, clean) = split_command(cmd);
+                (parts.into_iter().map(Some).collect(), clean)
+            }
+            None => (vec![None], true),
+        };
+
+        let mut verdict: Option<PolicyMatch> = None;
+        let mut unmatched = false;
+        for command in commands {
+            match self.first_match(tool, command, path.as_deref()) {
+                Some(found) => {
+                    if verdict.as_ref().is_none_or(|v| found.action > v.action) {
+                        verdict = Some(found);
+                    }
+                }
+                None => unmatched = true,
+            }
+        }
+
+        // A command we couldn't take apart may hide a part no rule saw, so unless a part is
+        // denied outright the user decides.
+        let has_rules = self.rules.iter().any(|rule| rule.tool == *tool);
+        if !clean
+            && has_rules
+            && verdict
+                .as_ref()
+                .is_none_or(|v| v.action < PolicyAction::Ask)
+        {
+            return Some(PolicyMatch {
+                action: PolicyAction::Ask,
+                rule: None,
+            });
+        }
+
+        // An allow only counts when it covers every part of the command.
+        match verdict {
+            Some(v) if unmatched && v.action == PolicyAction::Allow => None,
+            verdict => verdict,
+        }
+    }
+
+    fn first_match(
+        &self,
+        tool: &AmpTool,
+        command: Option<&str>,
+        path: Option<&str>,
+    ) -> Option<PolicyMatch<'_>> {
+        self.rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| {
+                rule.tool == *tool
+                    && rule.command.as_deref().is_none_or(|pattern| {
+                        command.is_some_and(|command| glob_match(pattern, command, None))
+                    })
+                    && rule.path.as_deref().is_none_or(|pattern| {
+                        path.is_some_and(|path| glob_match(pattern, path, Some('/')))
+                    })
+            })
+            .map(|(i, rule)| PolicyMatch {
+                action: rule.action,
+                rule: Some((i + 1, rule)),
+            })
+    }
+}
+
+/// Splits a shell command into the simple commands it runs: at `;`, `&`, `|` and newlines,
+/// and at the edges of subshells, groups, `$(...)` and backticks. Quoted text is left alone.
+/// The flag is false when the syntax doesn't balance, so some command may have been missed.
+fn split_command(cmd: &str) -> (Vec<&str>, bool) {
+    let bytes = cmd.as_bytes();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut parens = 0usize;
+    let mut braces = 0usize;
+    let mut expansions = 0usize;
+    let mut in_backticks = false;
+    let mut clean = true;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let next = bytes.get(i + 1).copied();
+        let separator = match (quote, c) {
+            (Some(b'\''), b'\'') => {
+                quote = None;
+                0
+            }
+            (Some(b'\''), _) => 0,
+            (_, b'\\') => {
+                i += 2;
+                continue;
+            }
+            (Some(b'"'), b'"') => {
+                quote = None;
+                0
+            }
+            (None, b'\'' | b'"') => {
+                quote = Some(c);
+                0
+            }
+            (_, b'`') => {
+                in_backticks = !in_backticks;
+                1
+            }
+            (_, b'$') if next == Some(b'(') => {
+                parens += 1;
+                2
+            }
+            (_, b'$') if next == Some(b'{') => {
+                expansions += 1;
+                i += 2;
+                continue;
+            }
+            (_, b')') if parens > 0 => {
+                parens -= 1;
+                1
+            }
+            (_, b'}') if expansions > 0 => {
+                expansions -= 1;
+                0
+            }
+            (Some(_), _) => 0,
+            (None, b'(') => {
+                parens += 1;
+                1
+            }
+            (None, b'{') => {
+                braces += 1;
+                1
+            }
+            (None, b'}') => {
+                match braces.checked_sub(1) {
+                    Some(depth) => braces = depth,
+                    None => clean = false,
+                }
+                1
+            }
+            (None, b')') => {
+                clean = false;
+                1
+            }
+            (None, b';' | b'|' | b'\n') => 1,
+            // `2>&1`, `<&3` and `&>file` are redirections, not background jobs.
+            (None, b'&')
+                if !(i > 0 && matches!(bytes[i - 1], b'>' | b'<')) && next != Some(b'>') =>
+            {
+                1
+            }
+            _ => 0,
+        };
+
+        if separator > 0 {
+            parts.push(&cmd[start..i]);
+            start = i + separator;
+        }
+        i += separator.max(1);
+    }
+    parts.push(&cmd[start.min(cmd.len())..]);
+
+    clean &= quote.is_none() && parens == 0 && braces == 0 && expansions == 0 && !in_backticks;
+    let parts = parts
+        .into_iter()
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let (command, simple) = strip_assignments(part);
+            clean &= simple && !runs_another_command(command);
+            command
+        })
+        .collect();
+    (parts, clean)
+}
+
+/// Drops leading `NAME=value` words so `FOO=1 git push` is checked as `git push`. A
+/// quoted value can't be skipped reliably, which the flag reports.
+fn strip_assignments(part: &str) -> (&str, bool) {
+    let mut rest = part;
+    loop {
+        let (word, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let Some((name, value)) = word.split_once('=') else {
+            return (rest, true);
+        };
+        let is_name = !name.is_empty()
+            && !name.starts_with(|c: char| c.is_ascii_digit())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_name {
+            return (rest, true);
+        }
+        if value.contains(['\'', '"']) {
+            return (rest, false);
+        }
+        if after.trim().is_empty() {
+            return (rest, true);
+        }
+        rest = after.trim_start();
+    }
+}
+
+/// Commands that run a command given as an argument, which the rules can't see into.
+fn runs_another_command(command: &str) -> bool {
+    let mut words = command.split_whitespace();
+    match words
+        .next()
+        .map(|word| word.rsplit('/').next().unwrap_or(word))
+    {
+        Some("eval" | "env" | "command" | "exec" | "xargs" | "sudo") => true,
+        Some("sh" | "bash" | "zsh" | "dash" | "ksh") => {
+            words.any(|word| word.starts_with('-') && !word.starts_with("--") && word.contains('c'))
+        }
+        _ => false,
+    }
+}
+
+/// Resolves `..` and `.` without touching the filesystem so `src/../secrets` can't slip
+/// past a `src/**` rule, then strips the cwd.
+fn relative_to(cwd: &Path, path: &str) -> String {
+    let mut resolved = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    resolved
+        .strip_prefix(cwd)
+        .unwrap_or(&resolved)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// `*` and `?` don't cross `separator`, `**` matches anything, and a trailing `/` matches
+/// everything below a directory. Without a separator `*` matches any text.
+fn glob_match(pattern: &str, text: &str, separator: Option<char>) -> bool {
+    let pattern = match pattern.strip_suffix('/') {
+        Some(dir) if separator.is_some() => format!("{dir}/**"),
+        _ => pattern.to_string(),
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_match_chars(&pattern, &text, separator)
+}
+
+fn glob_match_chars(pattern: &[char], text: &[char], separator: Option<char>) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // `src/**/x` also matches `src/x`.
+            if let [sep, after @ ..] = rest {
+                if Some(*sep) == separator && glob_match_chars(after, text, separator) {
+                    return true;
+                }
+            }
+            (0..=text.len()).any(|i| glob_match_chars(rest, &text[i..], separator))
+        }
+        ['*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if glob_match_chars(rest, &text[i..], separator) {
+                    return true;
+                }
+                if i < text.len() && Some(text[i]) == separator {
+                    break;
+                }
+            }
+            false
+        }
+        ['?', rest @ ..] => match text {
+            [c, text @ ..] if Some(*c) != separator => glob_match_chars(rest, text, separator),
+            _ => false,
+        },
+        [p, rest @ ..] => match text {
+            [c, text @ ..] if c == p => glob_match_chars(rest, text, separator),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(cmd: &str) -> Vec<&str> {
+        let (parts, clean) = split_command(cmd);
+        assert!(clean, "{cmd:?} should split cleanly");
+        parts
+    }
+
+    #[test]
+    fn splits_command_lists_and_pipelines() {
+        assert_eq!(
+            parts("cargo build && git push || echo no; ls | wc -l\ntrue"),
+            ["cargo build", "git push", "echo no", "ls", "wc -l", "true"]
+        );
+        assert_eq!(parts("true & git push"), ["true", "git push"]);
+    }
+
+    #[test]
+    fn splits_subshells_groups_and_substitutions() {
+        assert_eq!(parts("(git push)"), ["git push"]);
+        assert_eq!(parts("{ git push; }"), ["git push"]);
+        assert_eq!(parts("echo $(git push)"), ["echo", "git push"]);
+        assert_eq!(parts("echo `git push`"), ["echo", "git push"]);
+        assert_eq!(parts("echo \"$(git push)\""), ["echo \"", "git push", "\""]);
+    }
+
+    #[test]
+    fn keeps_quotes_redirections_and_expansions_together() {
+        assert_eq!(
+            parts("git commit -m 'a; b | c'"),
+            ["git commit -m 'a; b | c'"]
+        );
+        assert_eq!(parts("echo \"a && b\""), ["echo \"a && b\""]);
+        assert_eq!(parts("make 2>&1 &>log"), ["make 2>&1 &>log"]);
+        assert_eq!(parts(r"echo ${HOME} a\;b"), [r"echo ${HOME} a\;b"]);
+    }
+
+    #[test]
+    fn reports_unbalanced_commands() {
+        for cmd in [
+            "(git push",
+            "git push)",
+            "echo `git push",
+            "echo 'x",
+            "echo $(x",
+            "}",
+        ] {
+            assert!(!split_command(cmd).1, "{cmd:?} should not split cleanly");
+        }
+    }
+
+    #[test]
+    fn glob_separator_handling() {
+        let sep = Some('/');
+        assert!(glob_match("src/*.rs", "src/main.rs", sep));
+        assert!(!glob_match("src/*.rs", "src/a/main.rs", sep));
+        assert!(glob_match("src/?.rs", "src/a.rs", sep));
+        assert!(!glob_match("src?a.rs", "src/a.rs", sep));
+        // Commands have no separator, so `*` covers slashes.
+        assert!(glob_match("git push*", "git push origin feature/x", None));
+        assert!(!glob_match("git push*", "git status", None));
+    }
+
+    #[test]
+    fn glob_double_star_and_trailing_slash() {
+        let sep = Some('/');
+        assert!(glob_match("src/**/*.rs", "src/a/b/main.rs", sep));
+        assert!(glob_match("src/**/*.rs", "src/main.rs", sep));
+        assert!(glob_match("**", "any/where/at/all", sep));
+        assert!(glob_match("secrets/", "secrets/prod/key.pem", sep));
+        assert!(!glob_match("secrets/", "secrets.txt", sep));
+    }
+
+    #[test]
+    fn relative_paths_are_normalized() {
+        let cwd = Path::new("/work/project");
+        assert_eq!(relative_to(cwd, "src/../secrets/key"), "secrets/key");
+        assert_eq!(relative_to(cwd, "./src/./main.rs"), "src/main.rs");
+        assert_eq!(relative_to(cwd, "/work/project/src/lib.rs"), "src/lib.rs");
+        assert_eq!(relative_to(cwd, "../other/file"), "/work/other/file");
+    }
+
+    fn policy(rules: serde_json::Value) -> Policy {
+        serde_json::from_value(serde_json::json!({ "rules": rules })).unwrap()
+    }
+
+    #[test]
+    fn hidden_commands_are_still_denied() {
+        let policy = policy(serde_json::json!([
+            { "tool": "Bash", "action": "deny", "command": "git push*" },
+        ]));
+        for cmd in [
+            "true & git push",
+            "(git push)",
+            "echo $(git push)",
+            "echo `git push`",
+            "FOO=1 git push",
+        ] {
+            let verdict = policy.evaluate(
+                &AmpTool::Bash,
+                &serde_json::json!({ "cmd": cmd }),
+                Path::new("/"),
+            );
+            assert_eq!(
+                verdict.map(|v| v.action),
+                Some(PolicyAction::Deny),
+                "{cmd:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn command_wrappers_are_asked_about() {
+        let policy = policy(serde_json::json!([
+            { "tool": "Bash", "action": "deny", "command": "git push*" },
+        ]));
+        for cmd in [
+            "sh -c 'git push'",
+            "bash -c \"git push\"",
+            "/bin/bash -lc 'git push'",
+            "env git push",
+            "command git push",
+            "eval git push",
+            "FOO='a b' git push",
+        ] {
+            let verdict = policy.evaluate(
+                &AmpTool::Bash,
+                &serde_json::json!({ "cmd": cmd }),
+                Path::new("/"),
+            );
+            assert_eq!(
+                verdict.map(|v| v.action),
+                Some(PolicyAction::Ask),
+                "{cmd:?}"
+            );
+        }
+        // Running a script is not a wrapper.
+        assert!(!runs_another_command("bash build.sh"));
+    }
+
+    #[test]
+    fn unclear_commands_are_asked_about() {
+        let policy = policy(serde_json::json!([
+            { "tool": "Bash", "action": "allow", "command": "ls*" },
+        ]));
+        let verdict = policy.evaluate(
+            &AmpTool::Bash,
+            &serde_json::json!({ "cmd": "ls $(git push" }),
+            Path::new("/"),
+        );
+        assert_eq!(verdict.map(|v| v.action), Some(PolicyAction::Ask));
+    }
+}
@@ -0,0 +1,139 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::{Child, ChildStderr, Command};
+use tokio::task::JoinHandle;
+
+const DEFAULT_CANCEL_GRACE: Duration = Duration::from_secs(5);
+
+/// How much of amp's stderr is kept for error reports; older output is dropped.
+const STDERR_LIMIT: usize = 16 * 1024;
+
+/// How long amp gets to flush its thread after SIGINT before its process group is killed.
+/// Configurable through `AMP_ACP_CANCEL_GRACE_MS`.
+pub fn cancel_grace_period() -> Duration {
+    std::env::var("AMP_ACP_CANCEL_GRACE_MS")
+        .ok()
+        .and_then(|ms| ms.parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_CANCEL_GRACE)
+}
+
+/// Puts the spawned amp process into its own process group, so signals also reach
+/// the commands amp starts on our behalf.
+pub fn isolate_process_group(command: &mut Command) -> &mut Command {
+    #[cfg(unix)]
+    command.process_group(0);
+    command
+}
+
+#[cfg(unix)]
+fn signal_group(pgid: u32, signal: libc::c_int) -> io::Result<()> {
+    let res = unsafe { libc::killpg(pgid as libc::pid_t, signal) };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ESRCH) {
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Asks amp to stop. On unix this sends SIGINT to the whole process group; elsewhere the
+/// process is killed outright.
+pub fn interrupt(child: &mut Child) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        match child.id() {
+            Some(pid) => signal_group(pid, libc::SIGINT),
+            None => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        child.start_kill()
+    }
+}
+
+/// Kills every process left in the group led by `pgid`.
+pub fn kill_group(pgid: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        signal_group(pgid, libc::SIGKILL)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = pgid;
+        Ok(())
+    }
+}
+
+/// Drains amp's stderr while it runs, so a chatty process can't block on a full pipe,
+/// logging every line and keeping the most recent ones.
+pub struct StderrCapture {
+    tail: Rc<RefCell<VecDeque<String>>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl StderrCapture {
+    pub fn spawn(stderr: Option<ChildStderr>) -> Self {
+        let tail = Rc::new(RefCell::new(VecDeque::new()));
+        let task = stderr.map(|stderr| {
+            let tail = tail.clone();
+            tokio::task::spawn_local(async move {
+                let mut reader = BufReader::new(stderr);
+                let mut buf = Vec::new();
+                let mut size = 0;
+                loop {
+                    buf.clear();
+                    // Read bytes rather than lines: a single invalid UTF-8 line must not stop
+                    // the drain, or amp blocks once the pipe fills up. Overlong lines, like a
+                    // spinner redrawn with `\r`, are cut into pieces that fit the limit.
+                    let mut line_reader = (&mut reader).take(STDERR_LIMIT as u64);
+                    match line_reader.read_until(b'\n', &mut buf).await {
+                        Ok(0) => break,
+                        Ok(_) => {}
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                        Err(e) => {
+                            tracing::warn!("Failed to read amp's stderr: {e}");
+                            break;
+                        }
+                    }
+                    let line = String::from_utf8_lossy(&buf).trim_end().to_string();
+                    tracing::warn!("amp: {line}");
+                    let mut tail = tail.borrow_mut();
+                    size += line.len();
+                    tail.push_back(line);
+                    while size > STDERR_LIMIT && tail.len() > 1 {
+                        size -= tail.pop_front().map_or(0, |line| line.len());
+                    }
+                }
+            })
+        });
+        Self { tail, task }
+    }
+
+    /// The captured output once amp has exited. Helpers it started may still hold the
+    /// pipe open, so this doesn't wait long for the end of it.
+    pub async fn finish(mut self) -> String {
+        if let Some(task) = self.task.take() {
+            let _ = tokio::time::timeout(Duration::from_millis(500), task).await;
+        }
+        let tail = self.tail.borrow();
+        tail.iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Drop for StderrCapture {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
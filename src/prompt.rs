@@ -0,0 +1,245 @@
+use agent_client_protocol::{
+    AudioContent, BlobResourceContents, ContentBlock, EmbeddedResourceResource, Error,
+    ImageContent, ResourceLink, TextResourceContents,
+};
+use base64::prelude::*;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::attachments::{self, TurnAttachments};
+use crate::audio::AudioHandler;
+use crate::tool_result;
+
+const MAX_BLOB_BYTES: usize = 20 * 1024 * 1024;
+
+/// Renders ACP prompt content blocks into the text piped to `amp threads continue`.
+pub struct PromptBuilder<'a> {
+    cwd: &'a Path,
+    attachments: &'a mut TurnAttachments,
+    audio_handler: Option<Rc<dyn AudioHandler>>,
+    parts: Vec<String>,
+}
+
+impl<'a> PromptBuilder<'a> {
+    pub fn new(
+        cwd: &'a Path,
+        attachments: &'a mut TurnAttachments,
+        audio_handler: Option<Rc<dyn AudioHandler>>,
+    ) -> Self {
+        Self {
+            cwd,
+            attachments,
+            audio_handler,
+            parts: Vec::new(),
+        }
+    }
+
+    pub async fn push(&mut self, block: &ContentBlock) -> Result<(), Error> {
+        let part = match block {
+            ContentBlock::Text(text_content) => text_content.text.clone(),
+            ContentBlock::Image(image_content) => self.image(image_content)?,
+            ContentBlock::Audio(audio_content) => self.audio(audio_content).await?,
+            ContentBlock::ResourceLink(resource_link) => self.resource_link(resource_link),
+            ContentBlock::Resource(embedded_resource) => match &embedded_resource.resource {
+                EmbeddedResourceResource::TextResourceContents(text_resource_contents) => {
+                    self.text_resource(text_resource_contents)
+                }
+                EmbeddedResourceResource::BlobResourceContents(blob_resource_contents) => {
+                    self.blob_resource(blob_resource_contents)?
+                }
+            },
+        };
+
+        if !part.trim().is_empty() {
+            self.parts.push(part);
+        }
+        Ok(())
+    }
+
+    pub fn build(self) -> String {
+        self.parts.join("\n\n")
+    }
+
+    fn image(&mut self, image_content: &ImageContent) -> Result<String, Error> {
+        let bytes = BASE64_STANDARD
+            .decode(&image_content.data)
+            .map_err(|e| Error::invalid_params().with_data(format!("Invalid image data: {e}")))?;
+        let path = self.attach("image", &image_content.mime_type, &bytes)?;
+        Ok(format!("@{}", path.display()))
+    }
+
+    fn attach(&mut self, prefix: &str, mime_type: &str, bytes: &[u8]) -> Result<PathBuf, Error> {
+        self.attachments
+            .write(prefix, attachments::extension_for_mime(mime_type), bytes)
+            .map_err(|e| {
+                Error::internal_error().with_data(format!("Failed to save attachment: {e}"))
+            })
+    }
+
+    async fn audio(&mut self, audio_content: &AudioContent) -> Result<String, Error> {
+        let audio_handler = self.audio_handler.clone().ok_or_else(|| {
+            Error::invalid_params()
+                .with_data("Audio input is not supported: no audio transcriber is configured")
+        })?;
+        let bytes = BASE64_STANDARD
+            .decode(&audio_content.data)
+            .map_err(|e| Error::invalid_params().with_data(format!("Invalid audio data: {e}")))?;
+        let path = self.attach("audio", &audio_content.mime_type, &bytes)?;
+        audio_handler
+            .transcribe(&path, &audio_content.mime_type)
+            .await
+    }
+
+    fn resource_link(&self, resource_link: &ResourceLink) -> String {
+        match file_uri_to_path(&resource_link.uri) {
+            Some(path) => self.mention(&path),
+            None => format!("[{}]({})", resource_link.name, resource_link.uri),
+        }
+    }
+
+    fn text_resource(&self, resource: &TextResourceContents) -> String {
+        let label = match &resource.mime_type {
+            Some(mime_type) => format!("{} ({mime_type})", resource.uri),
+            None => resource.uri.clone(),
+        };
+        format!("{label}\n{}", tool_result::code_block("", &resource.text))
+    }
+
+    fn blob_resource(&mut self, resource: &BlobResourceContents) -> Result<String, Error> {
+        // Base64 encodes three bytes in four characters, so this bounds the decoded size.
+        let decoded_len = resource.blob.len() / 4 * 3;
+        if decoded_len > MAX_BLOB_BYTES {
+            return Err(Error::invalid_params().with_data(format!(
+                "Embedded resource {} is about {decoded_len} bytes, more than the {MAX_BLOB_BYTES} byte limit",
+                resource.uri
+            )));
+        }
+
+        let mut bytes = BASE64_STANDARD.decode(&resource.blob).map_err(|e| {
+            Error::invalid_params().with_data(format!(
+                "Invalid base64 in embedded resource {}: {e}",
+                resource.uri
+            ))
+        })?;
+        let mime_type = resource
+            .mime_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+
+        if is_text_mime(mime_type) {
+            match String::from_utf8(bytes) {
+                Ok(text) => {
+                    return Ok(self.text_resource(&TextResourceContents {
+                        mime_type: resource.mime_type.clone(),
+                        text,
+                        uri: resource.uri.clone(),
+                        meta: None,
+                    }));
+                }
+                // Not actually UTF-8, so hand it over as a file like any other binary.
+                Err(e) => bytes = e.into_bytes(),
+            }
+        }
+
+        if mime_type.starts_with("image/") {
+            let path = self.attach("image", mime_type, &bytes)?;
+            return Ok(format!("@{}", path.display()));
+        }
+
+        let path = self.attach("resource", mime_type, &bytes)?;
+        Ok(format!(
+            "{} ({mime_type}) saved to @{}",
+            resource.uri,
+            path.display()
+        ))
+    }
+
+    /// An @-mention amp resolves against the session cwd. Paths outside the cwd stay absolute.
+    fn mention(&self, path: &Path) -> String {
+        let path = path.strip_prefix(self.cwd).unwrap_or(path);
+        format!("@{}", path.display())
+    }
+}
+
+fn is_text_mime(mime_type: &str) -> bool {
+    let essence = mime_type.split(';').next().unwrap_or(mime_type).trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence,
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/typescript"
+                | "application/yaml"
+                | "application/x-yaml"
+                | "application/toml"
+                | "application/sql"
+                | "application/graphql"
+                | "application/x-sh"
+        )
+}
+
+fn file_uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let path = path.strip_prefix("localhost").unwrap_or(path);
+    let path = path.split(['#', '?']).next().unwrap_or(path);
+    Some(PathBuf::from(percent_decode(path)))
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = &bytes[i + 1..i + 3];
+            if hex.iter().all(u8::is_ascii_hexdigit) {
+                let hex = std::str::from_utf8(hex).expect("hex digits are ASCII");
+                decoded.push(u8::from_str_radix(hex, 16).expect("checked hex digits"));
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_handles_escapes() {
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        // Anything that isn't a complete escape is kept as it is.
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz%+1"), "%zz%+1");
+    }
+
+    #[test]
+    fn file_uris_become_paths() {
+        assert_eq!(
+            file_uri_to_path("file:///home/me/my%20file.rs"),
+            Some(PathBuf::from("/home/me/my file.rs"))
+        );
+        assert_eq!(
+            file_uri_to_path("file:///src/lib.rs#L10"),
+            Some(PathBuf::from("/src/lib.rs"))
+        );
+        assert_eq!(
+            file_uri_to_path("file:///src/lib.rs?line=3"),
+            Some(PathBuf::from("/src/lib.rs"))
+        );
+        assert_eq!(
+            file_uri_to_path("file://localhost/etc/hosts"),
+            Some(PathBuf::from("/etc/hosts"))
+        );
+        assert_eq!(file_uri_to_path("https://example.com/a"), None);
+    }
+}
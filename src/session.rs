@@ -0,0 +1,48 @@
+use agent_client_protocol::SessionId;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tokio::process::Child;
+use tokio_util::sync::CancellationToken;
+
+use crate::amp_agent::AmpTool;
+use crate::mode::AmpMode;
+use crate::settings::SettingsFile;
+use crate::usage::Usage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnState {
+    Idle,
+    Running,
+    Cancelled,
+}
+
+pub struct Session {
+    pub cwd: PathBuf,
+    // The client's MCP servers for this session, as an `amp.mcpServers` setting.
+    pub mcp_servers: serde_json::Value,
+    pub settings: SettingsFile,
+    pub child: Option<Child>,
+    pub turn: TurnState,
+    pub mode: AmpMode,
+    // Tool calls the user allowed for the rest of the session, see `permissions::allow_scope`.
+    pub always_allowed: HashSet<(AmpTool, Option<String>)>,
+    // Cancelled along with the turn, for work the adapter does on amp's behalf.
+    pub cancelled: CancellationToken,
+    pub usage: Usage,
+}
+
+impl Session {
+    pub fn new(session_id: &SessionId, cwd: PathBuf, mcp_servers: serde_json::Value) -> Self {
+        Self {
+            cwd,
+            mcp_servers,
+            settings: SettingsFile::new(&session_id.0),
+            child: None,
+            turn: TurnState::Idle,
+            mode: AmpMode::default(),
+            always_allowed: HashSet::new(),
+            cancelled: CancellationToken::new(),
+            usage: Usage::default(),
+        }
+    }
+}
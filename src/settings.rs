@@ -0,0 +1,236 @@
+use agent_client_protocol::McpServer;
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub fn amp_settings_path() -> PathBuf {
+    if cfg!(target_os = "windows") {
+        let appdata = env::var("APPDATA").unwrap_or_else(|_| ".".to_string());
+        PathBuf::from(appdata).join("amp").join("settings.json")
+    } else {
+        let home = env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        PathBuf::from(home)
+            .join(".config")
+            .join("amp")
+            .join("settings.json")
+    }
+}
+
+/// The user's settings. A broken file is an error: carrying on without it would silently
+/// drop their configuration for the turn.
+fn read_settings(path: &Path) -> io::Result<serde_json::Value> {
+    if !path.exists() {
+        return Ok(serde_json::json!({}));
+    }
+    let contents = std::fs::read_to_string(path)?;
+    serde_json::from_str(&contents).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not valid JSON: {e}", path.display()),
+        )
+    })
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so amp never sees
+/// a half-written file. The file is only readable by the user since it can hold secrets
+/// from MCP server environments.
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    use std::io::Write;
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{file_name}.{:x}.tmp", rand::random::<u64>()));
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let result = options.open(&tmp_path).and_then(|mut file| {
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// The `amp.mcpServers` setting for the MCP servers a client passed with a session.
+pub fn mcp_servers(servers: &[McpServer]) -> serde_json::Value {
+    let mcp_servers = servers
+        .iter()
+        .map(|server| match server {
+            McpServer::Stdio {
+                name,
+                command,
+                args,
+                env,
+            } => {
+                let env_obj: serde_json::Map<String, serde_json::Value> = env
+                    .iter()
+                    .map(|e| (e.name.clone(), serde_json::Value::String(e.value.clone())))
+                    .collect();
+
+                (
+                    name.clone(),
+                    serde_json::json!({
+                        "command": command,
+                        "args": args,
+                        "env": env_obj
+                    }),
+                )
+            }
+            McpServer::Http { name, url, headers } => {
+                let headers_obj: serde_json::Map<String, serde_json::Value> = headers
+                    .iter()
+                    .map(|h| (h.name.clone(), serde_json::Value::String(h.value.clone())))
+                    .collect();
+
+                (
+                    name.clone(),
+                    serde_json::json!({
+                        "type": "http",
+                        "url": url,
+                        "headers": headers_obj
+                    }),
+                )
+            }
+            McpServer::Sse { name, url, headers } => {
+                let headers_obj: serde_json::Map<String, serde_json::Value> = headers
+                    .iter()
+                    .map(|h| (h.name.clone(), serde_json::Value::String(h.value.clone())))
+                    .collect();
+
+                (
+                    name.clone(),
+                    serde_json::json!({
+                        "type": "sse",
+                        "url": url,
+                        "headers": headers_obj
+                    }),
+                )
+            }
+        })
+        .collect::<serde_json::Map<String, serde_json::Value>>();
+    serde_json::Value::Object(mcp_servers)
+}
+
+/// Layers `overlay` on top of the settings in `base`. Object settings such as
+/// `amp.mcpServers` are merged entry by entry, with overlay entries replacing the user's
+/// entries of the same name, and arrays are concatenated with the overlay's entries first
+/// so our permission rules take precedence.
+fn merge(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    if !base.is_object() || !overlay.is_object() {
+        *base = overlay;
+        return;
+    }
+    let (Some(base), serde_json::Value::Object(overlay)) = (base.as_object_mut(), overlay) else {
+        return;
+    };
+
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(serde_json::Value::Object(existing)), serde_json::Value::Object(entries)) => {
+                existing.extend(entries);
+            }
+            (Some(serde_json::Value::Array(existing)), serde_json::Value::Array(mut entries)) => {
+                entries.append(existing);
+                *existing = entries;
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// A session's private amp settings file, handed to amp through `--settings-file`. Each
+/// turn rewrites it with the user's settings plus that turn's overlay; it is removed when
+/// the session goes away.
+pub struct SettingsFile {
+    path: PathBuf,
+}
+
+impl SettingsFile {
+    pub fn new(session_id: &str) -> Self {
+        let path = env::temp_dir().join(format!(
+            "amp-acp-{}-{:x}.json",
+            session_id,
+            rand::random::<u64>()
+        ));
+        Self { path }
+    }
+
+    pub fn write(&self, overlay: serde_json::Value) -> io::Result<()> {
+        let mut settings = read_settings(&amp_settings_path())?;
+        merge(&mut settings, overlay);
+        write_atomic(&self.path, &serde_json::to_string_pretty(&settings)?)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SettingsFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn overlay_entries_take_precedence() {
+        let mut base = json!({
+            "amp.mcpServers": { "mine": { "command": "a" }, "shared": { "command": "old" } },
+            "amp.permissions": [{ "tool": "Bash", "action": "allow" }],
+            "amp.dangerouslyAllowAll": true,
+            "amp.notifications.enabled": false,
+        });
+        merge(
+            &mut base,
+            json!({
+                "amp.mcpServers": { "shared": { "command": "new" } },
+                "amp.permissions": [{ "tool": "Bash", "action": "delegate" }],
+                "amp.dangerouslyAllowAll": false,
+            }),
+        );
+        assert_eq!(
+            base,
+            json!({
+                "amp.mcpServers": { "mine": { "command": "a" }, "shared": { "command": "new" } },
+                "amp.permissions": [
+                    { "tool": "Bash", "action": "delegate" },
+                    { "tool": "Bash", "action": "allow" },
+                ],
+                "amp.dangerouslyAllowAll": false,
+                "amp.notifications.enabled": false,
+            })
+        );
+    }
+
+    #[test]
+    fn overlay_replaces_mismatched_values() {
+        let mut base = json!({ "amp.mcpServers": [], "amp.permissions": "broken" });
+        merge(
+            &mut base,
+            json!({ "amp.mcpServers": {}, "amp.permissions": [{ "tool": "Bash" }] }),
+        );
+        assert_eq!(
+            base,
+            json!({ "amp.mcpServers": {}, "amp.permissions": [{ "tool": "Bash" }] })
+        );
+
+        let mut base = json!(null);
+        merge(&mut base, json!({ "amp.mcpServers": {} }));
+        assert_eq!(base, json!({ "amp.mcpServers": {} }));
+    }
+}
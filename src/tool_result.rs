@@ -0,0 +1,259 @@
+//! Renders the results amp reports for its tools as markdown for the client.
+
+use serde_json::Value;
+use std::path::Path;
+
+use crate::amp_agent::AmpTool;
+
+/// The text shown for a finished tool call. Shapes we don't recognise fall back to
+/// pretty-printed JSON.
+pub fn format(tool: Option<&AmpTool>, input: Option<&Value>, result: &Value) -> String {
+    let formatted = match tool {
+        Some(AmpTool::Bash) => bash(result),
+        Some(AmpTool::Read) => read(input, result),
+        Some(AmpTool::Grep | AmpTool::Glob | AmpTool::Finder) => matches(result),
+        Some(AmpTool::WebSearch) => web_search(result),
+        _ => None,
+    };
+    formatted.unwrap_or_else(|| match result {
+        Value::String(text) => text.clone(),
+        result => code_block(
+            "json",
+            &serde_json::to_string_pretty(result).unwrap_or_default(),
+        ),
+    })
+}
+
+fn bash(result: &Value) -> Option<String> {
+    if let Value::String(output) = result {
+        return Some(code_block("", output));
+    }
+    let output = text_field(result, &["output", "stdout"]);
+    let stderr = text_field(result, &["stderr"]);
+    let exit_code = result.get("exitCode").or_else(|| result.get("exit_code"));
+    if output.is_none() && stderr.is_none() && exit_code.is_none() {
+        return None;
+    }
+
+    let mut text = String::new();
+    if let Some(output) = output.filter(|o| !o.is_empty()) {
+        text.push_str(&code_block("", output));
+        text.push('\n');
+    }
+    if let Some(stderr) = stderr.filter(|s| !s.is_empty()) {
+        text.push_str("stderr:\n");
+        text.push_str(&code_block("", stderr));
+        text.push('\n');
+    }
+    match exit_code {
+        Some(code) => text.push_str(&format!("Exit code: {code}")),
+        None => text = text.trim_end().to_string(),
+    }
+    Some(text)
+}
+
+fn read(input: Option<&Value>, result: &Value) -> Option<String> {
+    let content = match result {
+        Value::String(content) => content.as_str(),
+        result => text_field(result, &["content", "text"])?,
+    };
+    let path = input
+        .and_then(|input| input.get("path"))
+        .and_then(|p| p.as_str())
+        .or_else(|| text_field(result, &["absolutePath", "path"]));
+    let language = path.map(language_for_path).unwrap_or_default();
+    Some(code_block(language, content))
+}
+
+fn matches(result: &Value) -> Option<String> {
+    let items = match result {
+        Value::Array(items) => items,
+        result => ["matches", "files", "results"]
+            .iter()
+            .find_map(|key| result.get(key)?.as_array())?,
+    };
+    if items.is_empty() {
+        return Some("No matches".to_string());
+    }
+
+    let lines: Vec<String> = items
+        .iter()
+        .map(|item| match item {
+            Value::String(item) => format!("- `{item}`"),
+            item => {
+                let path = text_field(item, &["path", "file", "uri"]).unwrap_or_default();
+                let line = item.get("line").or_else(|| item.get("lineNumber"));
+                let text = text_field(item, &["text", "content", "match"]);
+                let location = match line {
+                    Some(line) => format!("{path}:{line}"),
+                    None => path.to_string(),
+                };
+                match text {
+                    Some(text) => format!("- `{location}`: {}", text.trim()),
+                    None => format!("- `{location}`"),
+                }
+            }
+        })
+        .collect();
+    Some(lines.join("\n"))
+}
+
+fn web_search(result: &Value) -> Option<String> {
+    let items = match result {
+        Value::Array(items) => items,
+        result => result.get("results")?.as_array()?,
+    };
+    if items.is_empty() {
+        return Some("No results".to_string());
+    }
+
+    let lines: Vec<String> = items
+        .iter()
+        .filter_map(|item| {
+            let url = text_field(item, &["url", "link"])?;
+            let title = text_field(item, &["title"]).unwrap_or(url);
+            let mut line = format!("- [{title}]({url})");
+            if let Some(snippet) = text_field(item, &["description", "snippet"]) {
+                line.push_str(&format!(": {}", snippet.trim()));
+            }
+            Some(line)
+        })
+        .collect();
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
+
+fn text_field<'a>(value: &'a Value, keys: &[&str]) -> Option<&'a str> {
+    keys.iter().find_map(|key| value.get(key)?.as_str())
+}
+
+/// A fenced block that can't be closed early by backticks in `text`.
+pub fn code_block(language: &str, text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat(longest.max(2) + 1);
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    format!("{fence}{language}\n{text}\n{fence}")
+}
+
+fn language_for_path(path: &str) -> &'static str {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    match extension {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "jsx" => "jsx",
+        "ts" | "mts" | "cts" => "typescript",
+        "tsx" => "tsx",
+        "go" => "go",
+        "java" => "java",
+        "kt" => "kotlin",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" => "cpp",
+        "cs" => "csharp",
+        "rb" => "ruby",
+        "php" => "php",
+        "swift" => "swift",
+        "sh" | "bash" | "zsh" => "bash",
+        "json" => "json",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        "md" => "markdown",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "sql" => "sql",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn bash_output_stderr_and_exit_code() {
+        let result = json!({ "output": "hi\n", "stderr": "oops", "exitCode": 1 });
+        assert_eq!(
+            format(Some(&AmpTool::Bash), None, &result),
+            "```\nhi\n```\nstderr:\n```\noops\n```\nExit code: 1"
+        );
+        assert_eq!(
+            format(Some(&AmpTool::Bash), None, &json!("plain")),
+            "```\nplain\n```"
+        );
+    }
+
+    #[test]
+    fn read_uses_the_language_of_the_path() {
+        let input = json!({ "path": "src/main.rs" });
+        assert_eq!(
+            format(
+                Some(&AmpTool::Read),
+                Some(&input),
+                &json!({ "content": "fn main() {}\n" })
+            ),
+            "```rust\nfn main() {}\n```"
+        );
+        assert_eq!(
+            format(
+                Some(&AmpTool::Read),
+                None,
+                &json!({ "content": "x", "absolutePath": "/a/notes.txt" })
+            ),
+            "```\nx\n```"
+        );
+    }
+
+    #[test]
+    fn grep_and_glob_list_matches() {
+        let result = json!({ "matches": [
+            { "path": "src/lib.rs", "line": 3, "text": "  fn foo()  " },
+            { "file": "README.md" },
+        ] });
+        assert_eq!(
+            format(Some(&AmpTool::Grep), None, &result),
+            "- `src/lib.rs:3`: fn foo()\n- `README.md`"
+        );
+        assert_eq!(
+            format(Some(&AmpTool::Glob), None, &json!(["a.rs", "b.rs"])),
+            "- `a.rs`\n- `b.rs`"
+        );
+        assert_eq!(format(Some(&AmpTool::Glob), None, &json!([])), "No matches");
+    }
+
+    #[test]
+    fn web_search_links_results() {
+        let result = json!({ "results": [
+            { "title": "Rust", "url": "https://rust-lang.org", "description": " A language " },
+            { "url": "https://example.com" },
+            { "title": "No link" },
+        ] });
+        assert_eq!(
+            format(Some(&AmpTool::WebSearch), None, &result),
+            "- [Rust](https://rust-lang.org): A language\n- [https://example.com](https://example.com)"
+        );
+    }
+
+    #[test]
+    fn unknown_results_fall_back_to_json() {
+        assert_eq!(
+            format(Some(&AmpTool::Task), None, &json!({ "a": 1 })),
+            "```json\n{\n  \"a\": 1\n}\n```"
+        );
+        assert_eq!(format(None, None, &json!("done")), "done");
+    }
+
+    #[test]
+    fn code_block_fence_outgrows_backticks() {
+        assert_eq!(code_block("", "a ` b"), "```\na ` b\n```");
+        assert_eq!(code_block("md", "```\nx\n```"), "````md\n```\nx\n```\n````");
+        assert_eq!(code_block("", "`````"), "``````\n`````\n``````");
+    }
+}
@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+
+/// The payload of amp's `result` stream event.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AmpResult {
+    #[serde(default)]
+    pub subtype: String,
+    #[serde(default)]
+    pub is_error: bool,
+    pub result: Option<String>,
+    pub error: Option<serde_json::Value>,
+    pub duration_ms: Option<u64>,
+    pub num_turns: Option<u64>,
+    pub total_cost_usd: Option<f64>,
+    pub usage: Option<AmpTokenUsage>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct AmpTokenUsage {
+    #[serde(default)]
+    pub input_tokens: u64,
+    #[serde(default)]
+    pub output_tokens: u64,
+    #[serde(default)]
+    pub cache_creation_input_tokens: u64,
+    #[serde(default)]
+    pub cache_read_input_tokens: u64,
+}
+
+/// Tokens, cost and time spent, for one turn or summed over a session.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cache_creation_input_tokens: u64,
+    pub cache_read_input_tokens: u64,
+    pub cost_usd: f64,
+    pub duration_ms: u64,
+    pub num_turns: u64,
+}
+
+impl AmpResult {
+    /// Whether the run ended early. An `error_*` subtype says so even when `is_error` doesn't.
+    pub fn failed(&self) -> bool {
+        self.is_error || self.subtype.starts_with("error")
+    }
+
+    /// What went wrong, when amp reports the run as failed.
+    pub fn error_message(&self) -> Option<String> {
+        if !self.failed() {
+            return None;
+        }
+        match &self.error {
+            Some(serde_json::Value::String(error)) => Some(error.clone()),
+            Some(error) => Some(
+                error
+                    .get("message")
+                    .and_then(|m| m.as_str())
+                    .map_or_else(|| error.to_string(), str::to_string),
+            ),
+            None => self.result.clone(),
+        }
+    }
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+        self.cost_usd += other.cost_usd;
+        self.duration_ms += other.duration_ms;
+        self.num_turns += other.num_turns;
+    }
+}
+
+impl From<&AmpResult> for Usage {
+    fn from(result: &AmpResult) -> Self {
+        let tokens = result.usage.clone().unwrap_or_default();
+        Self {
+            input_tokens: tokens.input_tokens,
+            output_tokens: tokens.output_tokens,
+            cache_creation_input_tokens: tokens.cache_creation_input_tokens,
+            cache_read_input_tokens: tokens.cache_read_input_tokens,
+            cost_usd: result.total_cost_usd.unwrap_or_default(),
+            duration_ms: result.duration_ms.unwrap_or_default(),
+            num_turns: result.num_turns.unwrap_or_default(),
+        }
+    }
+}